@@ -0,0 +1,101 @@
+use anyhow::Result;
+use clap::Parser;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use wasmtime::{Caller, Config, Engine, Linker, MemoryType, Module, SharedMemory, Store};
+
+/// compare reading the clock through the `get_now_nsec` import with reading
+/// it from a seqlock protected time page
+#[derive(Parser, Debug)]
+struct Args {
+    /// clock reads per measurement
+    #[clap(long, default_value_t = 10_000_000)]
+    reads: u32,
+}
+
+/// the time page layout of the runner, the sequence at 0 and the time at 8
+const GUEST: &str = r#"
+(module
+  (import "kernel" "get_now_nsec" (func $now (result i64)))
+  (import "env" "memory" (memory 1 1 shared))
+
+  (func (export "import") (param $reads i32) (result i64)
+    (local $sum i64)
+    (loop $read
+      (local.set $sum (i64.add (local.get $sum) (call $now)))
+      (br_if $read (local.tee $reads (i32.sub (local.get $reads) (i32.const 1)))))
+    (local.get $sum))
+
+  (func (export "page") (param $reads i32) (result i64)
+    (local $sum i64) (local $sequence i32) (local $time i64)
+    (loop $read
+      (loop $retry
+        (local.set $sequence (i32.atomic.load (i32.const 0)))
+        (local.set $time (i64.load (i32.const 8)))
+        (br_if $retry
+          (i32.or
+            (i32.and (local.get $sequence) (i32.const 1))
+            (i32.ne (local.get $sequence) (i32.atomic.load (i32.const 0))))))
+      (local.set $sum (i64.add (local.get $sum) (local.get $time)))
+      (br_if $read (local.tee $reads (i32.sub (local.get $reads) (i32.const 1)))))
+    (local.get $sum)))
+"#;
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut config = Config::new();
+    config.wasm_threads(true);
+    let engine = Engine::new(&config)?;
+    let module = Module::new(&engine, GUEST)?;
+    let memory = SharedMemory::new(&engine, MemoryType::shared(1, 1))?;
+
+    let time_origin = Instant::now();
+    let mut linker = Linker::new(&engine);
+    linker.func_wrap("kernel", "get_now_nsec", move |_: Caller<'_, ()>| {
+        time_origin.elapsed().as_nanos() as u64
+    })?;
+    linker.define(&Store::new(&engine, ()), "env", "memory", memory.clone())?;
+
+    // updates the page like the runner does
+    let stop = Arc::new(AtomicBool::new(false));
+    let updater = {
+        let memory = memory.clone();
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            let data = memory.data();
+            let sequence = unsafe { &*(data[0].get() as *const AtomicU32) };
+            let time = data[8].get() as *mut u64;
+            while !stop.load(Ordering::Relaxed) {
+                sequence.fetch_add(1, Ordering::SeqCst);
+                unsafe { time.write_volatile(time_origin.elapsed().as_nanos() as u64) };
+                sequence.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+
+    let mut store = Store::new(&engine, ());
+    let instance = linker.instantiate(&mut store, &module)?;
+    for name in ["import", "page"] {
+        let read = instance.get_typed_func::<u32, u64>(&mut store, name)?;
+        // warm up
+        read.call(&mut store, args.reads / 10 + 1)?;
+        let start = Instant::now();
+        read.call(&mut store, args.reads)?;
+        let elapsed = start.elapsed();
+        println!(
+            "{name:>6}: {:.1} ns per read",
+            elapsed.as_nanos() as f64 / f64::from(args.reads)
+        );
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    updater.join().unwrap();
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use anyhow::Result;
+use clap::Parser;
+use linux_wasm_runner::virtio::overlay;
+use std::path::PathBuf;
+
+/// write the changes in a drive overlay back into its base image
+#[derive(Parser, Debug)]
+struct Args {
+    /// path to the overlay file
+    overlay: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    overlay::commit(&args.overlay)
+}
@@ -0,0 +1,373 @@
+use crate::{lifecycle::Lifecycle, memory::GuestMemory};
+use anyhow::{bail, Context, Result};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{fence, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// how often the time page is updated in guest time, the resolution of clocks read from it
+const TIME_PAGE_PERIOD: Duration = Duration::from_millis(1);
+
+/// the time page at the address in `/chosen/time-page`
+///
+/// - 0x00 sequence, odd while the runner updates the page
+/// - 0x04 nanoseconds between updates
+/// - 0x08 `kernel.get_now_nsec` at the last update
+/// - 0x10 `kernel.get_realtime_nsec` at the last update
+///
+/// the guest reads the sequence, the times and the sequence again, and
+/// retries if it was odd or changed in between.
+///
+/// wasm has no cycle counter to extrapolate from, so the page is a coarse
+/// clock: its times lag the real ones by up to the period at 0x04, like
+/// `CLOCK_MONOTONIC_COARSE`. reads that need nanoseconds still go through
+/// `kernel.get_now_nsec` and `kernel.get_realtime_nsec`.
+pub(crate) const TIME_PAGE_LEN: u32 = 0x18;
+const SEQUENCE: u64 = 0x00;
+const PERIOD: u64 = 0x04;
+const MONOTONIC: u64 = 0x08;
+const REALTIME: u64 = 0x10;
+
+/// how the guest's clocks advance, the value of `--clock`
+#[derive(Clone, Copy, Debug, Default)]
+pub enum ClockMode {
+    /// with the host's monotonic clock
+    #[default]
+    HostReal,
+    /// `factor` guest nanoseconds per host nanosecond, 0.1 runs ten times slower
+    Scaled(f64),
+    /// only by instructions the guest executes, one nanosecond each, and
+    /// by [`Clock::advance`]. needs a single cpu to be deterministic
+    Virtual,
+}
+
+impl FromStr for ClockMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        Ok(match mode.split_once('=') {
+            None if mode == "host-real" => Self::HostReal,
+            None if mode == "virtual" => Self::Virtual,
+            Some(("scaled", factor)) => {
+                let factor = factor.parse::<f64>().context("invalid factor")?;
+                if !factor.is_finite() || factor <= 0.0 {
+                    bail!("the factor must be positive");
+                }
+                Self::Scaled(factor)
+            }
+            _ => bail!("unknown clock {mode}, expected host-real, scaled=<factor> or virtual"),
+        })
+    }
+}
+
+type AdvanceHook = Box<dyn Fn() + Send + Sync>;
+
+/// the guest's monotonic and wall clocks, replaced on every reboot
+pub struct Clock {
+    mode: ClockMode,
+    origin: Instant,
+    /// the wall clock time when the monotonic clock was at 0
+    realtime_origin: SystemTime,
+    /// the time of a virtual clock
+    virtual_nsec: AtomicU64,
+    /// called whenever a virtual clock advanced
+    advanced: Mutex<Vec<AdvanceHook>>,
+}
+
+impl Clock {
+    pub(crate) fn new(mode: ClockMode, realtime_origin: SystemTime) -> Self {
+        Self {
+            mode,
+            origin: Instant::now(),
+            realtime_origin,
+            virtual_nsec: AtomicU64::new(0),
+            advanced: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn mode(&self) -> ClockMode {
+        self.mode
+    }
+
+    /// nanoseconds since boot, what `kernel.get_now_nsec` returns
+    pub fn now(&self) -> u64 {
+        let elapsed = self.origin.elapsed().as_nanos();
+        match self.mode {
+            ClockMode::HostReal => {
+                u64::try_from(elapsed).expect("584 years would have to pass for this to overflow")
+            }
+            ClockMode::Scaled(factor) => (elapsed as f64 * factor) as u64,
+            ClockMode::Virtual => self.virtual_nsec.load(Ordering::SeqCst),
+        }
+    }
+
+    /// nanoseconds since the epoch, what `kernel.get_realtime_nsec` returns
+    pub fn realtime(&self) -> Result<u64> {
+        let since_epoch = self.realtime_origin.duration_since(UNIX_EPOCH)?;
+        Ok(u64::try_from(since_epoch.as_nanos())? + self.now())
+    }
+
+    /// step a virtual clock forward by `nsec`
+    pub fn advance(&self, nsec: u64) -> Result<()> {
+        if !matches!(self.mode, ClockMode::Virtual) {
+            bail!("only a virtual clock can be advanced");
+        }
+        self.virtual_nsec.fetch_add(nsec, Ordering::SeqCst);
+        for advanced in self.advanced.lock().unwrap().iter() {
+            advanced();
+        }
+        Ok(())
+    }
+
+    /// call `advanced` whenever a virtual clock moved
+    pub(crate) fn on_advance(&self, advanced: impl Fn() + Send + Sync + 'static) {
+        self.advanced.lock().unwrap().push(Box::new(advanced));
+    }
+
+    /// the host time until the clock reaches `deadline`, none for a
+    /// virtual clock
+    pub(crate) fn host_duration(&self, deadline: u64) -> Option<Duration> {
+        let nsec = deadline.saturating_sub(self.now());
+        match self.mode {
+            ClockMode::HostReal => Some(Duration::from_nanos(nsec)),
+            ClockMode::Scaled(factor) => Some(Duration::from_nanos((nsec as f64 / factor) as u64)),
+            ClockMode::Virtual => None,
+        }
+    }
+}
+
+/// keep the time page at `addr` up to date until the vm stops
+///
+/// a virtual clock only moves when it is advanced, so its page is updated
+/// right then instead of on a host timer.
+pub(crate) fn start_time_page(
+    memory: GuestMemory,
+    addr: u32,
+    clock: Arc<Clock>,
+    lifecycle: Arc<Lifecycle>,
+) -> Result<()> {
+    let addr = u64::from(addr);
+    memory.write_u32(addr + PERIOD, TIME_PAGE_PERIOD.as_nanos() as u32)?;
+    // a second writer would leave the sequence even mid update
+    let writer = Mutex::new(());
+    let update = move |clock: &Clock| -> Result<()> {
+        let _writer = writer.lock().unwrap();
+        let sequence = memory.atomic_u32(addr + SEQUENCE)?;
+        sequence.fetch_add(1, Ordering::SeqCst);
+        memory.write_u64(addr + MONOTONIC, clock.now())?;
+        memory.write_u64(addr + REALTIME, clock.realtime()?)?;
+        fence(Ordering::Release);
+        sequence.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    };
+    update(&clock)?;
+
+    let period = match clock.mode() {
+        ClockMode::HostReal => TIME_PAGE_PERIOD,
+        ClockMode::Scaled(factor) => TIME_PAGE_PERIOD.div_f64(factor),
+        ClockMode::Virtual => {
+            // the clock owns its hooks, a strong reference would never drop
+            let advanced = Arc::downgrade(&clock);
+            clock.on_advance(move || {
+                if let Some(clock) = advanced.upgrade() {
+                    if let Err(err) = update(&clock) {
+                        eprintln!("time page: {err:#}");
+                    }
+                }
+            });
+            return Ok(());
+        }
+    };
+
+    let stopped = lifecycle.clone();
+    let handle = std::thread::Builder::new()
+        .name(String::from("time page"))
+        .spawn(move || {
+            while !stopped.is_stopped() {
+                std::thread::sleep(period);
+                if let Err(err) = update(&clock) {
+                    eprintln!("time page: {err:#}");
+                    return;
+                }
+            }
+        })?;
+    lifecycle.track(handle);
+    Ok(())
+}
+
+/// the value of `--rtc-start`, `<yyyy>-<mm>-<dd>[T<hh>:<mm>:<ss>]` in utc
+/// or `@<seconds since the epoch>`
+#[derive(Clone, Copy, Debug)]
+pub struct RtcStart(pub SystemTime);
+
+impl FromStr for RtcStart {
+    type Err = anyhow::Error;
+
+    fn from_str(date: &str) -> Result<Self> {
+        let seconds = match date.strip_prefix('@') {
+            Some(seconds) => seconds.parse().context("invalid seconds")?,
+            None => parse_date(date).with_context(|| format!("invalid date {date}"))?,
+        };
+        Ok(Self(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)))
+    }
+}
+
+fn parse_date(date: &str) -> Result<u64> {
+    let (day, time) = date.split_once('T').unwrap_or((date, "00:00:00"));
+    let [year, month, day] = fields(day, '-')?;
+    let [hour, minute, second] = fields(time.trim_end_matches('Z'), ':')?;
+    if year < 1970 || !(1..=12).contains(&month) {
+        bail!("year or month out of range");
+    }
+    if !(1..=days_in_month(year, month)).contains(&day) {
+        bail!("day out of range");
+    }
+    if hour > 23 || minute > 59 || second > 59 {
+        bail!("time out of range");
+    }
+    Ok(days_since_epoch(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+fn fields(text: &str, separator: char) -> Result<[u64; 3]> {
+    let fields = text
+        .split(separator)
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()?;
+    fields
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected three fields"))
+}
+
+/// the number of days in `month` of `year`
+fn days_in_month(year: u64, month: u64) -> u64 {
+    let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// days from 1970-01-01 to a date in the proleptic gregorian calendar
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // years start in march, so the leap day is the last one
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use wasmtime::{Config, Engine, MemoryType, SharedMemory};
+
+    #[test]
+    fn virtual_time_page_follows_advances() {
+        let mut config = Config::new();
+        config.wasm_threads(true);
+        let engine = Engine::new(&config).unwrap();
+        let memory =
+            GuestMemory::new(SharedMemory::new(&engine, MemoryType::shared(1, 1)).unwrap());
+        let clock = Arc::new(Clock::new(ClockMode::Virtual, UNIX_EPOCH));
+        let lifecycle = Arc::new(Lifecycle::new(engine));
+        start_time_page(memory.clone(), 0x100, clock.clone(), lifecycle.clone()).unwrap();
+
+        // other hooks still run next to the time page's
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        clock.on_advance(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(memory.read_u64(0x100 + MONOTONIC).unwrap(), 0);
+        clock.advance(1234).unwrap();
+        assert_eq!(memory.read_u64(0x100 + MONOTONIC).unwrap(), 1234);
+        assert_eq!(memory.read_u64(0x100 + REALTIME).unwrap(), 1234);
+        assert_eq!(memory.read_u32(0x100 + SEQUENCE).unwrap(), 4);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // no thread updates a virtual page
+        assert_eq!(lifecycle.join(Duration::ZERO), 0);
+    }
+
+    fn seconds(date: &str) -> Result<u64> {
+        let RtcStart(start) = date.parse()?;
+        Ok(start.duration_since(UNIX_EPOCH)?.as_secs())
+    }
+
+    #[test]
+    fn rtc_start_seconds() {
+        assert_eq!(seconds("@0").unwrap(), 0);
+        assert_eq!(seconds("@1700000000").unwrap(), 1_700_000_000);
+        assert!(seconds("@").is_err());
+        assert!(seconds("@-1").is_err());
+        assert!(seconds("@12x").is_err());
+    }
+
+    #[test]
+    fn rtc_start_dates() {
+        assert_eq!(seconds("1970-01-01").unwrap(), 0);
+        assert_eq!(seconds("2000-03-01").unwrap(), 951_868_800);
+        assert_eq!(seconds("2024-02-29").unwrap(), 1_709_164_800);
+        assert_eq!(seconds("2024-02-29T12:34:56").unwrap(), 1_709_210_096);
+        assert_eq!(seconds("2024-02-29T12:34:56Z").unwrap(), 1_709_210_096);
+        assert_eq!(seconds("2038-01-19T03:14:08").unwrap(), 1 << 31);
+    }
+
+    #[test]
+    fn rtc_start_invalid_dates() {
+        for date in [
+            "",
+            "2024",
+            "2024-02",
+            "2024-02-29-01",
+            "1969-12-31",
+            "2024-00-10",
+            "2024-13-10",
+            "2024-01-00",
+            "2024-01-32",
+            "2024-04-31",
+            "2023-02-29",
+            "1900-02-29",
+            "2024-02-29T24:00:00",
+            "2024-02-29T12:60:00",
+            "2024-02-29T12:00:60",
+            "2024-02-29T12:00",
+            "2024-02-29 12:00:00",
+        ] {
+            assert!(seconds(date).is_err(), "{date}");
+        }
+        // 2000 is a leap year, 1900 is not
+        assert!(seconds("2000-02-29").is_ok());
+    }
+
+    #[test]
+    fn clock_modes() {
+        assert!(matches!("host-real".parse(), Ok(ClockMode::HostReal)));
+        assert!(matches!("virtual".parse(), Ok(ClockMode::Virtual)));
+        assert!(matches!("scaled=0.1".parse(), Ok(ClockMode::Scaled(factor)) if factor == 0.1));
+        assert!(matches!("scaled=4".parse(), Ok(ClockMode::Scaled(factor)) if factor == 4.0));
+        for mode in [
+            "",
+            "real",
+            "virtual=1",
+            "scaled",
+            "scaled=",
+            "scaled=0",
+            "scaled=-2",
+            "scaled=inf",
+            "scaled=NaN",
+            "scaled=fast",
+        ] {
+            assert!(mode.parse::<ClockMode>().is_err(), "{mode}");
+        }
+    }
+}
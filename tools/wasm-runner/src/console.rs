@@ -0,0 +1,599 @@
+use crate::{
+    irq::InterruptController,
+    lifecycle::{ExitReason, Lifecycle, ThreadStatus},
+};
+use anyhow::{bail, Context, Result};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    pty::{openpty, OpenptyResult},
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, OutputFlags, SetArg, Termios},
+    unistd::ttyname,
+};
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{stdin, stdout, ErrorKind, IsTerminal, Read, Write},
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd},
+        unix::{fs::FileTypeExt, net::UnixListener},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::{Duration, Instant},
+};
+
+/// terminal settings to restore, set while the host terminal is in raw mode
+static ORIGINAL_TERMIOS: Mutex<Option<Termios>> = Mutex::new(None);
+
+/// starts an escape sequence on the console input, ctrl-a like qemu
+const ESCAPE: u8 = 0x01;
+
+/// raised while console input is waiting for the guest
+pub(crate) const CONSOLE_IRQ: u32 = 1;
+
+/// input the guest did not read yet beyond this is dropped, so a paste or a
+/// client that writes without end cannot grow the runner without bound
+const MAX_INPUT: usize = 64 * 1024;
+
+const ESCAPE_HELP: &str = "\
+C-a h    print this help
+C-a x    exit the runner
+C-a b    send a break, the next key is a sysrq command
+C-a t    toggle console timestamps
+C-a s    show the state of the cpus
+C-a C-a  send C-a to the guest";
+
+/// where the guest console is connected to
+#[derive(Clone, Debug, Default)]
+pub enum ConsoleBackend {
+    /// the runner's stdin and stdout
+    #[default]
+    Stdio,
+    /// a unix socket accepting one client at a time
+    Unix(PathBuf),
+    /// a new pseudo terminal
+    Pty,
+}
+
+impl FromStr for ConsoleBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(backend: &str) -> Result<Self> {
+        match backend {
+            "stdio" => Ok(ConsoleBackend::Stdio),
+            "pty" => Ok(ConsoleBackend::Pty),
+            _ => match backend.strip_prefix("unix:") {
+                Some(path) => Ok(ConsoleBackend::Unix(PathBuf::from(path))),
+                None => bail!("expected stdio, pty or unix:<path>, got {backend}"),
+            },
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    Client(Box<dyn Write + Send>),
+    /// no client is connected, output is dropped
+    Detached,
+}
+
+struct Output {
+    sink: Sink,
+    /// whether the last byte written ended a line
+    line_start: bool,
+}
+
+/// a copy of everything the guest writes to the console
+struct Log {
+    file: File,
+    timestamps: bool,
+    line_start: bool,
+}
+
+/// the booted vm escape commands act on
+#[derive(Clone)]
+struct AttachedVm {
+    lifecycle: Arc<Lifecycle>,
+    time_origin: Instant,
+    interrupts: Arc<InterruptController>,
+}
+
+impl AttachedVm {
+    fn set_irq(&self, raised: bool) {
+        let result = if raised {
+            self.interrupts.raise(CONSOLE_IRQ)
+        } else {
+            self.interrupts.lower(CONSOLE_IRQ)
+        };
+        if let Err(err) = result {
+            eprintln!("while signalling console input: {err:#}");
+        }
+    }
+}
+
+/// the guest console, input is buffered until the guest reads it
+pub(crate) struct Console {
+    input: Mutex<VecDeque<u8>>,
+    break_pending: AtomicBool,
+    timestamps: AtomicBool,
+    output: Mutex<Output>,
+    log: Mutex<Option<Log>>,
+    /// the currently booted vm
+    vm: Mutex<Option<AttachedVm>>,
+}
+
+impl Console {
+    pub(crate) fn new(backend: &ConsoleBackend) -> Self {
+        let sink = match backend {
+            ConsoleBackend::Stdio => Sink::Stdout,
+            ConsoleBackend::Unix(_) | ConsoleBackend::Pty => Sink::Detached,
+        };
+        Self {
+            input: Mutex::default(),
+            break_pending: AtomicBool::default(),
+            timestamps: AtomicBool::default(),
+            output: Mutex::new(Output {
+                sink,
+                line_start: true,
+            }),
+            log: Mutex::default(),
+            vm: Mutex::default(),
+        }
+    }
+
+    /// connect the console to its backend
+    ///
+    /// returns the path of the pseudo terminal for [`ConsoleBackend::Pty`].
+    pub(crate) fn start(
+        self: &Arc<Self>,
+        backend: &ConsoleBackend,
+        interactive: bool,
+    ) -> Result<Option<PathBuf>> {
+        match backend {
+            ConsoleBackend::Stdio => {
+                if interactive {
+                    enter_raw_mode().context("while entering raw mode")?;
+                    self.forward_input(String::from("console"), stdin())?;
+                }
+                Ok(None)
+            }
+            ConsoleBackend::Unix(path) => {
+                self.listen(path)?;
+                Ok(None)
+            }
+            ConsoleBackend::Pty => self.open_pty().map(Some),
+        }
+    }
+
+    fn listen(self: &Arc<Self>, path: &Path) -> Result<()> {
+        // a socket left behind by an earlier run
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("while binding the console to {}", path.display()))?;
+
+        let console = self.clone();
+        std::thread::Builder::new()
+            .name(String::from("console"))
+            .spawn(move || {
+                for client in listener.incoming() {
+                    // a client that stops reading drops output instead of
+                    // blocking the guest
+                    let client = match client.and_then(|client| {
+                        client.set_nonblocking(true)?;
+                        Ok(client)
+                    }) {
+                        Ok(client) => client,
+                        Err(err) => {
+                            eprintln!("while accepting a console client: {err}");
+                            continue;
+                        }
+                    };
+                    match client.try_clone() {
+                        Ok(output) => console.set_sink(Sink::Client(Box::new(output))),
+                        Err(err) => {
+                            eprintln!("while accepting a console client: {err}");
+                            continue;
+                        }
+                    }
+                    console.read_input(PollReader(client));
+                    console.set_sink(Sink::Detached);
+                }
+            })?;
+        Ok(())
+    }
+
+    fn open_pty(self: &Arc<Self>) -> Result<PathBuf> {
+        let OpenptyResult { master, slave } = openpty(None, None)?;
+        let path = ttyname(&slave)?;
+
+        // the guest's tty does all line processing
+        let mut termios = tcgetattr(&slave)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(&slave, SetArg::TCSANOW, &termios)?;
+
+        // a full pty drops output instead of blocking the guest
+        fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        let master = File::from(master);
+        self.set_sink(Sink::Client(Box::new(master.try_clone()?)));
+
+        // holding the slave open keeps the master readable between clients
+        self.forward_input(
+            String::from("console"),
+            PtyReader {
+                master: PollReader(master),
+                _slave: slave,
+            },
+        )?;
+
+        Ok(path)
+    }
+
+    /// tee all guest output to `file`, optionally prefixing every line with
+    /// the time since boot
+    pub(crate) fn log_to(&self, file: File, timestamps: bool) {
+        *self.log.lock().unwrap() = Some(Log {
+            file,
+            timestamps,
+            line_start: true,
+        });
+    }
+
+    fn set_sink(&self, sink: Sink) {
+        self.output.lock().unwrap().sink = sink;
+    }
+
+    /// route escape commands to a freshly booted vm
+    pub(crate) fn attach(
+        &self,
+        lifecycle: Arc<Lifecycle>,
+        time_origin: Instant,
+        interrupts: Arc<InterruptController>,
+    ) {
+        let input = self.input.lock().unwrap();
+        let vm = AttachedVm {
+            lifecycle,
+            time_origin,
+            interrupts,
+        };
+        // input left over from the previous boot
+        if !input.is_empty() {
+            vm.set_irq(true);
+        }
+        *self.vm.lock().unwrap() = Some(vm);
+    }
+
+    fn timestamp(&self) -> String {
+        let elapsed = match &*self.vm.lock().unwrap() {
+            Some(vm) => vm.time_origin.elapsed(),
+            None => Duration::ZERO,
+        };
+        format!("[{:5}.{:06}] ", elapsed.as_secs(), elapsed.subsec_micros())
+    }
+
+    /// copy `bytes`, prefixing each line with a timestamp if `timestamps` is set
+    fn prefix_lines(&self, bytes: &[u8], timestamps: bool, line_start: &mut bool) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(bytes.len());
+        if timestamps {
+            for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+                if *line_start {
+                    buf.extend_from_slice(self.timestamp().as_bytes());
+                }
+                buf.extend_from_slice(line);
+                *line_start = line.ends_with(b"\n");
+            }
+        } else {
+            buf.extend_from_slice(bytes);
+            if let Some(&last) = bytes.last() {
+                *line_start = last == b'\n';
+            }
+        }
+        buf
+    }
+
+    pub(crate) fn write(&self, bytes: &[u8]) -> std::io::Result<()> {
+        if let Some(log) = &mut *self.log.lock().unwrap() {
+            let buf = self.prefix_lines(bytes, log.timestamps, &mut log.line_start);
+            log.file.write_all(&buf)?;
+        }
+
+        let mut output = self.output.lock().unwrap();
+        let output = &mut *output;
+        let timestamps = self.timestamps.load(Ordering::Relaxed);
+        let buf = self.prefix_lines(bytes, timestamps, &mut output.line_start);
+
+        match &mut output.sink {
+            Sink::Stdout => {
+                let mut stdout = stdout().lock();
+                stdout.write_all(&buf)?;
+                stdout.flush()
+            }
+            Sink::Client(client) => {
+                match client.write_all(&buf) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    // the client went away, the next one gets a fresh sink
+                    Err(_) => output.sink = Sink::Detached,
+                }
+                Ok(())
+            }
+            Sink::Detached => Ok(()),
+        }
+    }
+
+    /// tell whoever is on the console about an escape command
+    fn notice(&self, message: &str) {
+        let mut output = self.output.lock().unwrap();
+        match &mut output.sink {
+            Sink::Stdout => eprintln!("\n{message}"),
+            Sink::Client(client) => {
+                let message = format!("\r\n{}\r\n", message.replace('\n', "\r\n"));
+                let _ = client.write_all(message.as_bytes());
+            }
+            Sink::Detached => {}
+        }
+    }
+
+    /// take up to `len` pending input bytes without blocking
+    pub(crate) fn read(&self, len: usize) -> Vec<u8> {
+        let mut input = self.input.lock().unwrap();
+        let len = len.min(input.len());
+        let bytes = input.drain(..len).collect();
+        if input.is_empty() {
+            self.set_irq(false);
+        }
+        bytes
+    }
+
+    /// whether a break was sent since the last call
+    pub(crate) fn take_break(&self) -> bool {
+        self.break_pending.swap(false, Ordering::Relaxed)
+    }
+
+    pub(crate) fn push_input(&self, bytes: &[u8]) {
+        let mut input = self.input.lock().unwrap();
+        let room = MAX_INPUT.saturating_sub(input.len());
+        input.extend(&bytes[..bytes.len().min(room)]);
+        if !input.is_empty() {
+            self.set_irq(true);
+        }
+    }
+
+    /// raise or lower [`CONSOLE_IRQ`], called with the input locked
+    fn set_irq(&self, raised: bool) {
+        if let Some(vm) = &*self.vm.lock().unwrap() {
+            vm.set_irq(raised);
+        }
+    }
+
+    /// run the command following the escape key
+    fn escape(&self, command: u8) {
+        match command {
+            ESCAPE => self.push_input(&[ESCAPE]),
+            b'x' => {
+                self.notice("quit");
+                if let Some(vm) = &*self.vm.lock().unwrap() {
+                    vm.lifecycle.stop(ExitReason::Quit);
+                }
+            }
+            b'b' => self.break_pending.store(true, Ordering::Relaxed),
+            b't' => {
+                let enabled = !self.timestamps.fetch_xor(true, Ordering::Relaxed);
+                self.notice(if enabled {
+                    "timestamps on"
+                } else {
+                    "timestamps off"
+                });
+            }
+            b's' => self.dump_cpus(),
+            b'h' | b'?' => self.notice(ESCAPE_HELP),
+            _ => {}
+        }
+    }
+
+    fn dump_cpus(&self) {
+        let Some(vm) = self.vm.lock().unwrap().clone() else {
+            return;
+        };
+        let threads = vm.lifecycle.threads();
+        let stats = vm.interrupts.stats();
+
+        let mut dump = Vec::new();
+        for thread in threads.iter() {
+            if let Some(cpu) = thread.cpu {
+                let mut line = format!("cpu{cpu} ({}): {:?}", thread.name, thread.status);
+                if let Some(stats) = stats.get(cpu as usize) {
+                    let ipis = stats
+                        .ipis
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, &count)| count > 0)
+                        .map(|(kind, count)| format!("{kind}: {count}"))
+                        .collect::<Vec<_>>();
+                    line += &format!(
+                        ", {} irqs, {} ipis",
+                        stats.irqs,
+                        stats.ipis.iter().sum::<u64>()
+                    );
+                    if !ipis.is_empty() {
+                        line += &format!(" ({})", ipis.join(", "));
+                    }
+                }
+                dump.push(line);
+            }
+        }
+        let workers = threads.iter().filter(|thread| thread.cpu.is_none());
+        let running = workers
+            .clone()
+            .filter(|thread| matches!(thread.status, ThreadStatus::Running))
+            .count();
+        dump.push(format!(
+            "workers: {running} running, {} total",
+            workers.count()
+        ));
+        self.notice(&dump.join("\n"));
+    }
+
+    /// pass input to the guest until `reader` is closed, handling escapes
+    fn read_input(&self, mut reader: impl Read) {
+        let mut buf = [0; 256];
+        let mut escaped = false;
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    eprintln!("while reading console input: {err}");
+                    break;
+                }
+            };
+
+            let mut input = Vec::with_capacity(len);
+            for &byte in &buf[..len] {
+                if escaped {
+                    escaped = false;
+                    self.push_input(&input);
+                    input.clear();
+                    self.escape(byte);
+                } else if byte == ESCAPE {
+                    escaped = true;
+                } else {
+                    input.push(byte);
+                }
+            }
+            self.push_input(&input);
+        }
+    }
+
+    fn forward_input(
+        self: &Arc<Self>,
+        name: String,
+        reader: impl Read + Send + 'static,
+    ) -> std::io::Result<()> {
+        let console = self.clone();
+        std::thread::Builder::new()
+            .name(name)
+            .spawn(move || console.read_input(reader))?;
+        Ok(())
+    }
+}
+
+/// blocking reads from a non-blocking descriptor
+struct PollReader<R>(R);
+
+impl<R: Read + AsFd> Read for PollReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let mut fds = [PollFd::new(self.0.as_fd(), PollFlags::POLLIN)];
+            poll(&mut fds, PollTimeout::NONE)?;
+            match self.0.read(buf) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+struct PtyReader {
+    master: PollReader<File>,
+    _slave: OwnedFd,
+}
+
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.master.read(buf)
+    }
+}
+
+/// put the host terminal into raw mode so every keystroke reaches the guest
+///
+/// does nothing if stdin is not a terminal. the terminal is restored by
+/// [`restore_terminal`], which also runs when the runner panics.
+fn enter_raw_mode() -> nix::Result<()> {
+    let stdin = stdin();
+    if !stdin.is_terminal() {
+        return Ok(());
+    }
+
+    let mut original = ORIGINAL_TERMIOS.lock().unwrap();
+    if original.is_some() {
+        return Ok(());
+    }
+
+    let termios = tcgetattr(&stdin)?;
+    let mut raw = termios.clone();
+    cfmakeraw(&mut raw);
+    // keep translating newlines, the boot console does not emit carriage returns
+    raw.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+    tcsetattr(&stdin, SetArg::TCSAFLUSH, &raw)?;
+    *original = Some(termios);
+    drop(original);
+
+    static PANIC_HOOK: Once = Once::new();
+    PANIC_HOOK.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            hook(info);
+        }));
+    });
+
+    Ok(())
+}
+
+/// undo [`enter_raw_mode`], safe to call on every exit path
+pub fn restore_terminal() {
+    let Ok(mut original) = ORIGINAL_TERMIOS.lock() else {
+        return;
+    };
+    if let Some(termios) = original.take() {
+        if let Err(err) = tcsetattr(stdin(), SetArg::TCSAFLUSH, &termios) {
+            eprintln!("while restoring the terminal: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::GuestMemory;
+    use wasmtime::{Config, Engine, MemoryType, SharedMemory};
+
+    #[test]
+    fn input_raises_the_console_irq_until_drained() {
+        let mut config = Config::new();
+        config.wasm_threads(true);
+        let engine = Engine::new(&config).unwrap();
+        let memory =
+            GuestMemory::new(SharedMemory::new(&engine, MemoryType::shared(1, 1)).unwrap());
+        let interrupts = Arc::new(InterruptController::new(memory.clone(), 0, 1));
+        let console = Console::new(&ConsoleBackend::Unix(PathBuf::new()));
+        console.attach(
+            Arc::new(Lifecycle::new(engine)),
+            Instant::now(),
+            interrupts.clone(),
+        );
+        let pending = || memory.read_u32(0).unwrap() & 1 << CONSOLE_IRQ != 0;
+
+        console.push_input(b"");
+        assert!(!pending());
+        console.push_input(b"ab");
+        assert!(pending());
+        assert_eq!(console.read(1), b"a");
+        assert!(pending());
+        assert_eq!(console.read(8), b"b");
+        assert!(!pending());
+
+        // a flood is cut off at the cap
+        console.push_input(&vec![b'x'; MAX_INPUT + 100]);
+        assert_eq!(console.read(usize::MAX).len(), MAX_INPUT);
+        assert!(!pending());
+    }
+}
@@ -0,0 +1,362 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+
+/// writes a newc cpio archive as understood by the kernel's initramfs unpacker
+struct CpioWriter {
+    buf: Vec<u8>,
+    next_ino: u32,
+}
+
+impl CpioWriter {
+    fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            next_ino: 1,
+        }
+    }
+
+    fn pad(&mut self) {
+        self.buf.resize(self.buf.len().next_multiple_of(4), 0);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn entry(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        nlink: u32,
+        mtime: u32,
+        rdev: (u32, u32),
+        data: &[u8],
+    ) -> Result<()> {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+
+        let fields = [
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            mtime,
+            u32::try_from(data.len()).context("file is too large for cpio")?,
+            0,
+            0,
+            rdev.0,
+            rdev.1,
+            // the name is nul terminated
+            name.len() as u32 + 1,
+            0,
+        ];
+        self.buf.extend_from_slice(b"070701");
+        for field in fields {
+            self.buf
+                .extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        self.buf.extend_from_slice(name);
+        self.buf.push(0);
+        self.pad();
+        self.buf.extend_from_slice(data);
+        self.pad();
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>> {
+        self.next_ino = 0;
+        self.entry(b"TRAILER!!!", 0, 0, 0, 1, 0, (0, 0), &[])?;
+        Ok(self.buf)
+    }
+}
+
+fn dev_major(dev: u64) -> u32 {
+    (((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff)) as u32
+}
+
+fn dev_minor(dev: u64) -> u32 {
+    (((dev >> 12) & 0xffff_ff00) | (dev & 0xff)) as u32
+}
+
+/// add every entry below `dir`, in a stable order and parents first
+fn add_dir(cpio: &mut CpioWriter, root: &Path, dir: &Path) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("while reading {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = path.strip_prefix(root)?.as_os_str().as_bytes();
+        let metadata = fs::symlink_metadata(&path)?;
+        let mode = metadata.mode();
+        let mtime = metadata.mtime() as u32;
+
+        // files are owned by root in the guest, whoever owns them on the host
+        match mode & S_IFMT {
+            S_IFDIR => {
+                cpio.entry(name, mode, 0, 0, 2, mtime, (0, 0), &[])?;
+                add_dir(cpio, root, &path)?;
+            }
+            S_IFREG => {
+                let data =
+                    fs::read(&path).with_context(|| format!("while reading {}", path.display()))?;
+                cpio.entry(name, mode, 0, 0, 1, mtime, (0, 0), &data)?;
+            }
+            S_IFLNK => {
+                let target = fs::read_link(&path)?;
+                let target = target.as_os_str().as_bytes();
+                cpio.entry(name, mode, 0, 0, 1, mtime, (0, 0), target)?;
+            }
+            _ => {
+                let rdev = (dev_major(metadata.rdev()), dev_minor(metadata.rdev()));
+                cpio.entry(name, mode, 0, 0, 1, mtime, rdev, &[])?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_octal(field: &str) -> Result<u32> {
+    Ok(u32::from_str_radix(field, 8)?)
+}
+
+fn add_manifest_entry(cpio: &mut CpioWriter, fields: &[&str]) -> Result<()> {
+    match fields {
+        [] => {}
+        [comment, ..] if comment.starts_with('#') => {}
+        ["dir", name, mode, uid, gid] => {
+            let mode = S_IFDIR | parse_octal(mode)?;
+            let name = name.trim_start_matches('/').as_bytes();
+            cpio.entry(name, mode, uid.parse()?, gid.parse()?, 2, 0, (0, 0), &[])?;
+        }
+        ["nod", name, mode, uid, gid, kind, major, minor] => {
+            let kind = match *kind {
+                "c" => S_IFCHR,
+                "b" => S_IFBLK,
+                _ => bail!("unknown device type {kind}"),
+            };
+            let mode = kind | parse_octal(mode)?;
+            let name = name.trim_start_matches('/').as_bytes();
+            let rdev = (major.parse()?, minor.parse()?);
+            cpio.entry(name, mode, uid.parse()?, gid.parse()?, 1, 0, rdev, &[])?;
+        }
+        ["slink", name, target, mode, uid, gid] => {
+            let mode = S_IFLNK | parse_octal(mode)?;
+            let name = name.trim_start_matches('/').as_bytes();
+            let (uid, gid) = (uid.parse()?, gid.parse()?);
+            cpio.entry(name, mode, uid, gid, 1, 0, (0, 0), target.as_bytes())?;
+        }
+        _ => bail!("malformed entry"),
+    }
+
+    Ok(())
+}
+
+/// add the entries of a manifest in the format of the kernel's gen_init_cpio:
+///
+/// ```text
+/// # comment
+/// dir <name> <mode> <uid> <gid>
+/// nod <name> <mode> <uid> <gid> <c|b> <major> <minor>
+/// slink <name> <target> <mode> <uid> <gid>
+/// ```
+fn add_manifest(cpio: &mut CpioWriter, manifest: &str) -> Result<()> {
+    for (index, line) in manifest.lines().enumerate() {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        add_manifest_entry(cpio, &fields)
+            .with_context(|| format!("in manifest line {}", index + 1))?;
+    }
+
+    Ok(())
+}
+
+/// build a newc cpio archive from a host directory and an optional manifest
+pub(crate) fn from_dir(dir: &Path, manifest: Option<&Path>) -> Result<Vec<u8>> {
+    let mut cpio = CpioWriter::new();
+    add_dir(&mut cpio, dir, dir)?;
+
+    if let Some(manifest) = manifest {
+        let manifest = fs::read_to_string(manifest)
+            .with_context(|| format!("while reading {}", manifest.display()))?;
+        add_manifest(&mut cpio, &manifest)?;
+    }
+
+    cpio.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_LEN: usize = 110;
+
+    #[derive(Debug)]
+    struct Entry {
+        /// ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+        /// rdevmajor, rdevminor, namesize and check
+        fields: [u32; 13],
+        name: Vec<u8>,
+        data: Vec<u8>,
+    }
+
+    /// parse a newc archive, checking that the padding is zeros
+    fn entries(archive: &[u8]) -> Vec<Entry> {
+        let padding = |from: usize| {
+            let to = from.next_multiple_of(4);
+            assert!(archive[from..to].iter().all(|&byte| byte == 0));
+            to
+        };
+
+        let mut entries = Vec::new();
+        let mut at = 0;
+        while at < archive.len() {
+            assert_eq!(at % 4, 0);
+            let header = std::str::from_utf8(&archive[at..at + HEADER_LEN]).unwrap();
+            assert_eq!(&header[..6], "070701");
+            let fields: [u32; 13] = std::array::from_fn(|index| {
+                let field = &header[6 + index * 8..][..8];
+                u32::from_str_radix(field, 16).unwrap()
+            });
+
+            let name_start = at + HEADER_LEN;
+            let name_end = name_start + fields[11] as usize;
+            assert_eq!(archive[name_end - 1], 0);
+            let data_start = padding(name_end);
+            let data_end = data_start + fields[6] as usize;
+            entries.push(Entry {
+                fields,
+                name: archive[name_start..name_end - 1].to_vec(),
+                data: archive[data_start..data_end].to_vec(),
+            });
+            at = padding(data_end);
+        }
+        entries
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let mut cpio = CpioWriter::new();
+        cpio.entry(
+            b"bin/sh",
+            S_IFREG | 0o755,
+            1000,
+            100,
+            1,
+            0x6543_2100,
+            (0, 0),
+            b"#!",
+        )
+        .unwrap();
+        cpio.entry(b"dev/tty", S_IFCHR | 0o666, 0, 5, 1, 0, (5, 0x1234), &[])
+            .unwrap();
+        let entries = entries(&cpio.finish().unwrap());
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].name, b"bin/sh");
+        assert_eq!(
+            entries[0].fields,
+            [
+                1,
+                S_IFREG | 0o755,
+                1000,
+                100,
+                1,
+                0x6543_2100,
+                2,
+                0,
+                0,
+                0,
+                0,
+                7,
+                0
+            ]
+        );
+        assert_eq!(entries[0].data, b"#!");
+        assert_eq!(entries[1].name, b"dev/tty");
+        assert_eq!(entries[1].fields[0], 2);
+        assert_eq!(entries[1].fields[3], 5);
+        assert_eq!(entries[1].fields[9..11], [5, 0x1234]);
+        assert_eq!(entries[2].name, b"TRAILER!!!");
+        assert_eq!(entries[2].fields[0], 0);
+    }
+
+    #[test]
+    fn names_and_data_are_padded() {
+        let mut cpio = CpioWriter::new();
+        // every name length modulo 4 with data of every length modulo 4
+        for len in 1..=8 {
+            let name = vec![b'a' + len as u8; len];
+            let data = vec![len as u8; len];
+            cpio.entry(&name, S_IFREG | 0o644, 0, 0, 1, 0, (0, 0), &data)
+                .unwrap();
+        }
+        let archive = cpio.finish().unwrap();
+        assert_eq!(archive.len() % 4, 0);
+
+        let entries = entries(&archive);
+        for (len, entry) in (1..=8).zip(&entries) {
+            assert_eq!(entry.name, vec![b'a' + len as u8; len]);
+            assert_eq!(entry.data, vec![len as u8; len]);
+        }
+    }
+
+    #[test]
+    fn manifest_lines() {
+        let mut cpio = CpioWriter::new();
+        let manifest = "\
+            # device nodes\n\
+            \n\
+            dir /dev 755 0 0\n\
+            nod /dev/console 600 0 5 c 5 1\n\
+            nod /dev/vda 660 0 6 b 254 0\n\
+            slink /bin/sh busybox 777 1 2\n";
+        add_manifest(&mut cpio, manifest).unwrap();
+        let entries = entries(&cpio.finish().unwrap());
+
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0].name, b"dev");
+        assert_eq!(entries[0].fields[1], S_IFDIR | 0o755);
+        assert_eq!(entries[0].fields[4], 2);
+
+        assert_eq!(entries[1].name, b"dev/console");
+        assert_eq!(entries[1].fields[1], S_IFCHR | 0o600);
+        assert_eq!(entries[1].fields[2..4], [0, 5]);
+        assert_eq!(entries[1].fields[9..11], [5, 1]);
+        assert_eq!(entries[2].fields[1], S_IFBLK | 0o660);
+        assert_eq!(entries[2].fields[9..11], [254, 0]);
+
+        assert_eq!(entries[3].name, b"bin/sh");
+        assert_eq!(entries[3].fields[1], S_IFLNK | 0o777);
+        assert_eq!(entries[3].fields[2..4], [1, 2]);
+        assert_eq!(entries[3].data, b"busybox");
+    }
+
+    #[test]
+    fn malformed_manifest_lines() {
+        for line in [
+            "dir /dev 755 0",
+            "dir /dev 855 0 0",
+            "nod /dev/null 666 0 0 p 1 3",
+            "slink /bin/sh busybox 777 root 0",
+            "file /init init 755 0 0",
+        ] {
+            let manifest = format!("dir /proc 555 0 0\n{line}\n");
+            let err = add_manifest(&mut CpioWriter::new(), &manifest).unwrap_err();
+            assert_eq!(err.to_string(), "in manifest line 2", "{line}");
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{console::CONSOLE_IRQ, timer::TIMER_IRQ, virtio::WINDOW_SIZE};
+use anyhow::Result;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{collections::HashMap, fs::File, path::Path};
+use vm_fdt::FdtWriter;
+
+const INTC_PHANDLE: u32 = 1;
+
+pub type Sections = HashMap<String, (u32, u32)>;
+
+/// read the sections json file emitted by the kernel build
+pub fn load_sections(path: impl AsRef<Path>) -> Result<Sections> {
+    Ok(serde_json::from_reader(File::open(path)?)?)
+}
+
+/// the machine described by the devicetree
+pub struct Platform<'a> {
+    pub cmdline: &'a str,
+    pub sections: &'a Sections,
+    /// memory the kernel may allocate from, starting at 0
+    pub ram_bytes: u32,
+    pub ncpus: u32,
+    /// start and end of the initrd
+    pub initrd: Option<(u32, u32)>,
+    /// start and length of the interrupt controller's memory
+    pub interrupt_controller: (u32, u32),
+    /// base addresses and irqs of the virtio-mmio register windows
+    pub virtio_mmio: Vec<(u32, u32)>,
+    /// address of the time page, a clock with the resolution of its update
+    /// period, its layout is in `clock.rs`
+    pub time_page: u32,
+    /// derive `rng-seed` from this instead of host entropy
+    pub rng_seed: Option<u64>,
+}
+
+pub fn create_devicetree(platform: &Platform) -> Result<Vec<u8>> {
+    let Platform {
+        cmdline,
+        sections,
+        ram_bytes,
+        ncpus,
+        initrd,
+        interrupt_controller,
+        time_page,
+        ref virtio_mmio,
+        rng_seed: seed,
+    } = *platform;
+
+    let mut fdt = FdtWriter::new()?;
+    let mut rng_seed = [0u64; 8];
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed).fill(&mut rng_seed),
+        None => rand::thread_rng().fill(&mut rng_seed),
+    }
+
+    let root = fdt.begin_node("root")?;
+
+    fdt.property_u32("#address-cells", 1)?;
+    fdt.property_u32("#size-cells", 1)?;
+    fdt.property_u32("interrupt-parent", INTC_PHANDLE)?;
+
+    let chosen = fdt.begin_node("chosen")?;
+    fdt.property_array_u64("rng-seed", &rng_seed)?;
+    fdt.property_string("bootargs", cmdline)?;
+    fdt.property_u32("ncpus", ncpus)?;
+    fdt.property_u32("time-page", time_page)?;
+    if let Some((start, end)) = initrd {
+        fdt.property_u32("linux,initrd-start", start)?;
+        fdt.property_u32("linux,initrd-end", end)?;
+    }
+
+    let data_sections = fdt.begin_node("sections")?;
+    for (name, &(start, end)) in sections {
+        fdt.property_array_u32(name, &[start, end])?;
+    }
+    fdt.end_node(data_sections)?;
+
+    fdt.end_node(chosen)?;
+
+    let aliases = fdt.begin_node("aliases")?;
+    fdt.end_node(aliases)?;
+
+    let memory = fdt.begin_node("memory")?;
+    fdt.property_string("device_type", "memory")?;
+    fdt.property_array_u32("reg", &[0, ram_bytes])?;
+    fdt.end_node(memory)?;
+
+    let (intc_base, intc_len) = interrupt_controller;
+    let intc = fdt.begin_node(&format!("interrupt-controller@{intc_base:x}"))?;
+    fdt.property_string("compatible", "wasm,interrupt-controller")?;
+    fdt.property_array_u32("reg", &[intc_base, intc_len])?;
+    fdt.property_null("interrupt-controller")?;
+    fdt.property_u32("#interrupt-cells", 1)?;
+    fdt.property_u32("phandle", INTC_PHANDLE)?;
+    fdt.end_node(intc)?;
+
+    let timer = fdt.begin_node("timer")?;
+    fdt.property_string("compatible", "wasm,timer")?;
+    fdt.property_u32("interrupts", TIMER_IRQ)?;
+    fdt.end_node(timer)?;
+
+    let console = fdt.begin_node("console")?;
+    fdt.property_string("compatible", "wasm,console")?;
+    fdt.property_u32("interrupts", CONSOLE_IRQ)?;
+    fdt.end_node(console)?;
+
+    for &(base, irq) in virtio_mmio {
+        let virtio = fdt.begin_node(&format!("virtio_mmio@{base:x}"))?;
+        fdt.property_string("compatible", "virtio,mmio")?;
+        fdt.property_array_u32("reg", &[base, WINDOW_SIZE])?;
+        fdt.property_null("dma-coherent")?;
+        fdt.property_u32("interrupts", irq)?;
+        fdt.end_node(virtio)?;
+    }
+
+    fdt.end_node(root)?;
+
+    Ok(fdt.finish()?)
+}
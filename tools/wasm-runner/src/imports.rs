@@ -0,0 +1,296 @@
+use crate::{
+    clock::ClockMode,
+    lifecycle::{ExitReason, Stopped},
+    memory::GuestMemory,
+    vm::{spawn, State},
+};
+use anyhow::{bail, Result};
+use nix::sys::signal::{raise, Signal};
+use wasmtime::{Caller, Linker};
+
+/// advance a virtual clock by the instructions this thread executed since
+/// it last looked at the clock
+fn charge_fuel(caller: &mut Caller<'_, State>) -> Result<()> {
+    if !matches!(caller.data().clock.mode(), ClockMode::Virtual) {
+        return Ok(());
+    }
+    let fuel = caller.get_fuel()?;
+    let State {
+        clock, fuel: last, ..
+    } = caller.data_mut();
+    let used = last.saturating_sub(fuel);
+    *last = fuel;
+    if used > 0 {
+        clock.advance(used)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn add_imports(linker: &mut Linker<State>) -> Result<()> {
+    linker.func_wrap("kernel", "breakpoint", move || {
+        raise(Signal::SIGTRAP).unwrap();
+    })?;
+    linker.func_wrap(
+        "kernel",
+        "halt",
+        |caller: Caller<'_, State>| -> Result<()> {
+            eprintln!("halt");
+            caller.data().lifecycle.stop(ExitReason::PowerOff);
+            Err(Stopped.into())
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "restart",
+        |caller: Caller<'_, State>| -> Result<()> {
+            eprintln!("restart");
+            caller.data().lifecycle.stop(ExitReason::Reboot);
+            Err(Stopped.into())
+        },
+    )?;
+
+    linker.func_wrap(
+        "kernel",
+        "boot_console_write",
+        |mut caller: Caller<'_, State>, msg: u32, len: u32| {
+            let State {
+                memory, console, ..
+            } = caller.data_mut();
+
+            let msg = msg as usize;
+            let len = len as usize;
+
+            let slice = &memory.data()[msg..][..len];
+            let slice = unsafe {
+                &slice
+                    .iter()
+                    .map(|cell| {
+                        *cell
+                            .get()
+                            .as_ref()
+                            .expect("wasm memory is not a null pointer")
+                    })
+                    .collect::<Vec<_>>()
+            };
+            console.write(slice)?;
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "boot_console_read",
+        |caller: Caller<'_, State>, buf: u32, len: u32| -> Result<u32> {
+            let State {
+                memory, console, ..
+            } = caller.data();
+
+            let input = console.read(len as usize);
+            GuestMemory::new(memory.clone()).write(u64::from(buf), &input)?;
+            Ok(input.len() as u32)
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "boot_console_poll_break",
+        |caller: Caller<'_, State>| caller.data().console.take_break() as u32,
+    )?;
+    linker.func_wrap("kernel", "boot_console_close", || {
+        eprintln!("console closed");
+    })?;
+
+    linker.func_wrap(
+        "kernel",
+        "virtio_mmio_notify",
+        |caller: Caller<'_, State>, base: u32, offset: u32| {
+            caller.data().virtio.notify(base, offset)
+        },
+    )?;
+
+    linker.func_wrap(
+        "kernel",
+        "irq_eoi",
+        |caller: Caller<'_, State>, irq: u32| caller.data().interrupts.eoi(irq),
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "irq_set_target",
+        |caller: Caller<'_, State>, irq: u32, cpu: u32| {
+            caller.data().interrupts.set_target(irq, cpu)
+        },
+    )?;
+
+    linker.func_wrap(
+        "kernel",
+        "send_ipi",
+        |caller: Caller<'_, State>, cpu: u32, kind: u32| {
+            caller.data().interrupts.send_ipi(cpu, kind)
+        },
+    )?;
+
+    linker.func_wrap("kernel", "return_address", |_frames: i32| -1)?;
+
+    linker.func_wrap(
+        "boot",
+        "get_devicetree",
+        |mut caller: Caller<'_, State>, buf: u32, len: u32| {
+            let State {
+                ref mut memory,
+                devicetree,
+                ..
+            } = caller.data_mut();
+            let memory = memory.data();
+            let buf = buf as usize;
+            let len = (len as usize).min(devicetree.len());
+            for i in 0..len {
+                unsafe {
+                    *memory[buf + i].get() = devicetree[i];
+                }
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "get_now_nsec",
+        |mut caller: Caller<'_, State>| -> Result<u64> {
+            charge_fuel(&mut caller)?;
+            Ok(caller.data().clock.now())
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "get_realtime_nsec",
+        |mut caller: Caller<'_, State>| {
+            charge_fuel(&mut caller)?;
+            caller.data().clock.realtime()
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "timer_arm",
+        |mut caller: Caller<'_, State>, cpu: u32, deadline: u64| {
+            charge_fuel(&mut caller)?;
+            caller.data().timers.arm(cpu, deadline)
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "timer_cancel",
+        |caller: Caller<'_, State>, cpu: u32| caller.data().timers.cancel(cpu),
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "wait_for_interrupt",
+        |mut caller: Caller<'_, State>, timeout: u64| -> Result<u32> {
+            charge_fuel(&mut caller)?;
+            let State {
+                cpu,
+                clock,
+                interrupts,
+                timers,
+                lifecycle,
+                ..
+            } = caller.data();
+            let Some(cpu) = *cpu else {
+                bail!("only cpus can wait for interrupts");
+            };
+            // u64::MAX waits until something arrives
+            let forever = timeout == u64::MAX;
+            let deadline = clock.now().saturating_add(timeout);
+
+            let woken = if interrupts.is_pending(cpu)? {
+                true
+            } else if let Some(timeout) = clock.host_duration(deadline) {
+                interrupts.wait(cpu, (!forever).then_some(timeout))?
+            } else {
+                // time stands still while the cpu idles, skip to its next event
+                match timers.deadline(cpu).filter(|&at| at <= deadline) {
+                    Some(at) => {
+                        clock.advance(at.saturating_sub(clock.now()))?;
+                        interrupts.wait(cpu, None)?
+                    }
+                    None if forever => interrupts.wait(cpu, None)?,
+                    None => {
+                        clock.advance(timeout)?;
+                        false
+                    }
+                }
+            };
+
+            if lifecycle.is_stopped() {
+                return Err(Stopped.into());
+            }
+            Ok(woken.into())
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "get_stacktrace",
+        |mut caller: Caller<'_, State>, buf: u32, len: u32| {
+            let memory = caller.data_mut().memory.data();
+
+            let trace = b"stack traces are unsupported";
+
+            let buf = buf as usize;
+            let len = (len as usize).min(trace.len());
+            for i in 0..len {
+                unsafe {
+                    *memory[buf..][i].get() = trace[i];
+                }
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "kernel",
+        "new_worker",
+        |mut caller: Caller<'_, State>, task: u32, comm: u32, comm_len: u32| {
+            let memory = caller.data_mut().memory.data();
+            let comm = comm as usize;
+            let comm_len = comm_len as usize;
+            let mut name = Vec::with_capacity(comm_len);
+
+            for i in 0..comm_len {
+                unsafe {
+                    name.push(*memory[comm + i].get());
+                }
+            }
+
+            let data = caller.data().clone();
+            let instance_pre = data
+                .instance_pre
+                .clone()
+                .expect("instance_pre is intialized before the first call");
+            let engine = caller.engine();
+
+            let name = String::from_utf8_lossy(&name).into_owned();
+            spawn(engine, data, name, None, move |store| {
+                instance_pre
+                    .instantiate(&mut *store)?
+                    .get_typed_func::<u32, ()>(&mut *store, "task")?
+                    .call(&mut *store, task)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        "kernel",
+        "bringup_secondary",
+        |caller: Caller<'_, State>, cpu: u32, idle: u32| {
+            let data = caller.data().clone();
+            let instance_pre = data
+                .instance_pre
+                .clone()
+                .expect("instance_pre is intialized before the first call");
+            let engine = caller.engine();
+
+            let name = format!("entry{cpu}");
+            spawn(engine, data, name, Some(cpu), move |store| {
+                instance_pre
+                    .instantiate(&mut *store)?
+                    .get_typed_func::<(u32, u32), ()>(&mut *store, "secondary")?
+                    .call(&mut *store, (cpu, idle))
+            })
+        },
+    )?;
+
+    Ok(())
+}
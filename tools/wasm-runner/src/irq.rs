@@ -0,0 +1,240 @@
+//! the interrupt controller
+//!
+//! every cpu has a block of `CPU_BLOCK_SIZE` bytes in guest memory, right
+//! after each other at the address in the devicetree's `interrupt-controller`
+//! node:
+//!
+//! - 0x00 pending irq lines 0-31, one bit per line
+//! - 0x04 pending irq lines 32-63
+//! - 0x08 bumped whenever something becomes pending, an idle cpu waits on it
+//!   with `memory.atomic.wait32`
+//! - 0x0c pending inter-processor interrupts, one bit per kind
+//!
+//! the guest takes interrupts by atomically clearing their pending bits. a
+//! level triggered line that is still raised pends again once the guest
+//! calls `kernel.irq_eoi`. ipis from `kernel.send_ipi` are taken the same way.
+
+use crate::memory::GuestMemory;
+use anyhow::{bail, Result};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+pub const NR_IRQS: u32 = 64;
+pub const NR_IPIS: u32 = 32;
+/// lines below this are sent to a cpu by the runner itself, e.g. the timer
+pub(crate) const FIRST_DEVICE_IRQ: u32 = 16;
+
+pub(crate) const CPU_BLOCK_SIZE: u32 = 0x40;
+const PENDING: u64 = 0x00;
+const WAKE: u64 = 0x08;
+const IPIS: u64 = 0x0c;
+
+/// what a cpu received since boot
+#[derive(Clone, Copy, Debug)]
+pub struct CpuStats {
+    pub irqs: u64,
+    /// ipis by kind
+    pub ipis: [u64; NR_IPIS as usize],
+}
+
+#[derive(Default)]
+struct Counters {
+    irqs: AtomicU64,
+    ipis: [AtomicU64; NR_IPIS as usize],
+}
+
+struct Lines {
+    /// lines that are raised right now
+    raised: u64,
+    /// the cpu every line is delivered to
+    targets: [u32; NR_IRQS as usize],
+}
+
+/// routes irq lines to cpus and wakes them up
+pub struct InterruptController {
+    memory: GuestMemory,
+    base: u64,
+    ncpus: u32,
+    lines: Mutex<Lines>,
+    counters: Vec<Counters>,
+    /// set once the vm stopped, nothing waits from then on
+    stopped: AtomicBool,
+}
+
+impl InterruptController {
+    pub(crate) fn new(memory: GuestMemory, base: u32, ncpus: u32) -> Self {
+        Self {
+            memory,
+            base: u64::from(base),
+            ncpus,
+            lines: Mutex::new(Lines {
+                raised: 0,
+                targets: [0; NR_IRQS as usize],
+            }),
+            counters: (0..ncpus).map(|_| Counters::default()).collect(),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// the guest memory needed for `ncpus` cpus
+    pub(crate) fn region_len(ncpus: u32) -> u32 {
+        ncpus * CPU_BLOCK_SIZE
+    }
+
+    fn check(&self, irq: u32, cpu: u32) -> Result<()> {
+        if irq >= NR_IRQS {
+            bail!("irq {irq} does not exist");
+        }
+        if cpu >= self.ncpus {
+            bail!("cpu {cpu} does not exist");
+        }
+        Ok(())
+    }
+
+    fn block(&self, cpu: u32) -> u64 {
+        self.base + u64::from(cpu * CPU_BLOCK_SIZE)
+    }
+
+    /// the pending word of `irq` on `cpu`, and the bit in it
+    fn pending_bit(&self, irq: u32, cpu: u32) -> (u64, u32) {
+        (
+            self.block(cpu) + PENDING + u64::from(irq / 32 * 4),
+            1 << (irq % 32),
+        )
+    }
+
+    fn pend(&self, irq: u32, cpu: u32) -> Result<()> {
+        let (word, bit) = self.pending_bit(irq, cpu);
+        self.memory
+            .atomic_u32(word)?
+            .fetch_or(bit, Ordering::SeqCst);
+        self.counters[cpu as usize]
+            .irqs
+            .fetch_add(1, Ordering::Relaxed);
+        self.wake(cpu)
+    }
+
+    /// wake `cpu` if it waits for an interrupt
+    pub(crate) fn wake(&self, cpu: u32) -> Result<()> {
+        let wake = self.block(cpu) + WAKE;
+        self.memory.atomic_u32(wake)?.fetch_add(1, Ordering::SeqCst);
+        self.memory.wake(wake)
+    }
+
+    /// whether `cpu` has an irq or ipi it did not take yet
+    pub(crate) fn is_pending(&self, cpu: u32) -> Result<bool> {
+        self.check(0, cpu)?;
+        let block = self.block(cpu);
+        for word in [PENDING, PENDING + 4, IPIS] {
+            if self.memory.atomic_u32(block + word)?.load(Ordering::SeqCst) != 0 {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// park the calling thread until something is pending on `cpu` or the
+    /// controller stopped, returns false if `timeout` passed first
+    pub(crate) fn wait(&self, cpu: u32, timeout: Option<Duration>) -> Result<bool> {
+        let wake = self.block(cpu) + WAKE;
+        // anything pending after this bumps the word and ends the wait
+        let seen = self.memory.atomic_u32(wake)?.load(Ordering::SeqCst);
+        if self.is_pending(cpu)? || self.stopped.load(Ordering::SeqCst) {
+            return Ok(true);
+        }
+        self.memory.wait(wake, seen, timeout)
+    }
+
+    /// wake every cpu for good, once the vm stopped
+    pub(crate) fn stop(&self) -> Result<()> {
+        self.stopped.store(true, Ordering::SeqCst);
+        for cpu in 0..self.ncpus {
+            self.wake(cpu)?;
+        }
+        Ok(())
+    }
+
+    /// raise the level triggered line `irq` on its target cpu
+    pub fn raise(&self, irq: u32) -> Result<()> {
+        self.check(irq, 0)?;
+        let mut lines = self.lines.lock().unwrap();
+        lines.raised |= 1 << irq;
+        self.pend(irq, lines.targets[irq as usize])
+    }
+
+    /// lower `irq`, the guest no longer sees it if it did not take it yet
+    pub fn lower(&self, irq: u32) -> Result<()> {
+        self.check(irq, 0)?;
+        let mut lines = self.lines.lock().unwrap();
+        lines.raised &= !(1 << irq);
+        let (word, bit) = self.pending_bit(irq, lines.targets[irq as usize]);
+        self.memory
+            .atomic_u32(word)?
+            .fetch_and(!bit, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// an edge triggered interrupt `irq` on `cpu`, whatever the line's target
+    pub fn send(&self, irq: u32, cpu: u32) -> Result<()> {
+        self.check(irq, cpu)?;
+        self.pend(irq, cpu)
+    }
+
+    /// deliver `irq` to `cpu` from now on
+    pub fn set_target(&self, irq: u32, cpu: u32) -> Result<()> {
+        self.check(irq, cpu)?;
+        let mut lines = self.lines.lock().unwrap();
+        let old = std::mem::replace(&mut lines.targets[irq as usize], cpu);
+        // a raised line that was not taken yet moves along
+        let (word, bit) = self.pending_bit(irq, old);
+        let was_pending = self
+            .memory
+            .atomic_u32(word)?
+            .fetch_and(!bit, Ordering::SeqCst)
+            & bit
+            != 0;
+        if was_pending {
+            self.pend(irq, cpu)?;
+        }
+        Ok(())
+    }
+
+    /// an inter-processor interrupt of `kind` on `cpu`
+    pub fn send_ipi(&self, cpu: u32, kind: u32) -> Result<()> {
+        if kind >= NR_IPIS {
+            bail!("ipi kind {kind} does not exist");
+        }
+        self.check(0, cpu)?;
+        self.memory
+            .atomic_u32(self.block(cpu) + IPIS)?
+            .fetch_or(1 << kind, Ordering::SeqCst);
+        self.counters[cpu as usize].ipis[kind as usize].fetch_add(1, Ordering::Relaxed);
+        self.wake(cpu)
+    }
+
+    /// what every cpu received since boot
+    pub fn stats(&self) -> Vec<CpuStats> {
+        self.counters
+            .iter()
+            .map(|counters| CpuStats {
+                irqs: counters.irqs.load(Ordering::Relaxed),
+                ipis: std::array::from_fn(|kind| counters.ipis[kind].load(Ordering::Relaxed)),
+            })
+            .collect()
+    }
+
+    /// the guest handled `irq`, pend it again if it is still raised
+    pub(crate) fn eoi(&self, irq: u32) -> Result<()> {
+        self.check(irq, 0)?;
+        let lines = self.lines.lock().unwrap();
+        if lines.raised & 1 << irq != 0 {
+            self.pend(irq, lines.targets[irq as usize])?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+mod clock;
+mod console;
+mod cpio;
+mod devicetree;
+mod imports;
+mod irq;
+mod lifecycle;
+mod memory;
+#[cfg(test)]
+mod testing;
+mod timer;
+pub mod virtio;
+mod vm;
+
+pub use clock::{Clock, ClockMode, RtcStart};
+pub use console::{restore_terminal, ConsoleBackend};
+pub use devicetree::{create_devicetree, load_sections, Platform, Sections};
+pub use irq::{CpuStats, InterruptController, NR_IPIS, NR_IRQS};
+pub use lifecycle::ExitReason;
+pub use memory::GuestMemory;
+pub use vm::{handle_result, State, Vm, VmBuilder};
@@ -0,0 +1,194 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use wasmtime::Engine;
+
+/// why a vm stopped running
+#[derive(Debug)]
+pub enum ExitReason {
+    /// the guest called `kernel.halt`, or the boot cpu returned
+    PowerOff,
+    /// the guest called `kernel.restart`
+    Reboot,
+    /// a cpu or worker thread trapped
+    Trap {
+        thread: String,
+        error: anyhow::Error,
+    },
+    /// a cpu or worker thread panicked in the runner, only seen when the
+    /// embedder's panic strategy unwinds
+    Panic { thread: String, message: String },
+    /// the user quit through the console escape sequence
+    Quit,
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::PowerOff => write!(f, "power off"),
+            ExitReason::Reboot => write!(f, "reboot"),
+            ExitReason::Trap { thread, error } => write!(f, "in {thread}: {error}"),
+            ExitReason::Panic { thread, message } => write!(f, "panic in {thread}: {message}"),
+            ExitReason::Quit => write!(f, "quit"),
+        }
+    }
+}
+
+/// returned from imports to unwind the calling thread once the vm is stopping
+#[derive(Debug)]
+pub(crate) struct Stopped;
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vm stopped")
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ThreadStatus {
+    Running,
+    Exited,
+    /// interrupted because the vm stopped
+    Stopped,
+    Trapped,
+}
+
+/// a thread running guest code
+#[derive(Clone)]
+pub(crate) struct ThreadInfo {
+    pub(crate) name: String,
+    pub(crate) cpu: Option<u32>,
+    pub(crate) status: ThreadStatus,
+}
+
+type StopHook = Box<dyn Fn() + Send + Sync>;
+
+/// shared between every thread of a vm, records the first exit reason
+pub(crate) struct Lifecycle {
+    engine: Engine,
+    threads: Mutex<Vec<ThreadInfo>>,
+    /// every thread started for the vm, joined before a reboot
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    stopped: AtomicBool,
+    reason: Mutex<Option<ExitReason>>,
+    reason_set: Condvar,
+    /// wake threads blocked in the runner rather than in guest code
+    stop_hooks: Mutex<Vec<StopHook>>,
+}
+
+impl Lifecycle {
+    pub(crate) fn new(engine: Engine) -> Self {
+        Self {
+            engine,
+            threads: Mutex::new(Vec::new()),
+            handles: Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+            reason: Mutex::new(None),
+            reason_set: Condvar::new(),
+            stop_hooks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// stop the vm, only the first reason is kept
+    pub(crate) fn stop(&self, reason: ExitReason) {
+        let mut current = self.reason.lock().unwrap();
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        *current = Some(reason);
+        // every store traps at its next epoch check
+        self.engine.increment_epoch();
+        self.reason_set.notify_all();
+        drop(current);
+
+        for hook in self.stop_hooks.lock().unwrap().iter() {
+            hook();
+        }
+    }
+
+    /// call `hook` once the vm stops, right away if it already did
+    pub(crate) fn on_stop(&self, hook: impl Fn() + Send + Sync + 'static) {
+        let mut hooks = self.stop_hooks.lock().unwrap();
+        if self.is_stopped() {
+            drop(hooks);
+            hook();
+        } else {
+            hooks.push(Box::new(hook));
+        }
+    }
+
+    /// track a new thread, returns its id
+    pub(crate) fn register(&self, name: String, cpu: Option<u32>) -> usize {
+        let mut threads = self.threads.lock().unwrap();
+        threads.push(ThreadInfo {
+            name,
+            cpu,
+            status: ThreadStatus::Running,
+        });
+        threads.len() - 1
+    }
+
+    /// join `handle` before the vm reboots
+    pub(crate) fn track(&self, handle: JoinHandle<()>) {
+        self.handles.lock().unwrap().push(handle);
+    }
+
+    /// wait up to `timeout` for every thread of a stopped vm to exit,
+    /// returns how many are left running
+    ///
+    /// epoch interruption does not reach a thread blocked in a guest
+    /// `memory.atomic.wait32` without a timeout, such threads are detached
+    /// and keep their memory until the process exits.
+    pub(crate) fn join(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut left = 0;
+        // threads may start others until they notice the vm stopped
+        loop {
+            let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+            if handles.is_empty() {
+                return left;
+            }
+            for handle in handles {
+                while !handle.is_finished() && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                if handle.is_finished() {
+                    let _ = handle.join();
+                } else {
+                    left += 1;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn set_status(&self, id: usize, status: ThreadStatus) {
+        self.threads.lock().unwrap()[id].status = status;
+    }
+
+    pub(crate) fn threads(&self) -> Vec<ThreadInfo> {
+        self.threads.lock().unwrap().clone()
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// block until the vm stopped and take the exit reason
+    pub(crate) fn wait(&self) -> ExitReason {
+        let mut reason = self.reason.lock().unwrap();
+        loop {
+            if let Some(reason) = reason.take() {
+                return reason;
+            }
+            reason = self.reason_set.wait(reason).unwrap();
+        }
+    }
+}
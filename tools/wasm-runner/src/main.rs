@@ -1,19 +1,17 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
-use nix::sys::signal::{raise, Signal};
-use rand::Rng;
-use std::collections::HashMap;
-use std::{
-    fs::File,
-    io::{stdout, Write},
-    path::PathBuf,
-    time::Instant,
-};
-use vm_fdt::FdtWriter;
-use wasmtime::{
-    Caller, Config, Engine, InstancePre, Linker, MemoryType, Module, SharedMemory, Store,
-    WasmBacktraceDetails, WasmCoreDump,
+use linux_wasm_runner::{
+    virtio::{
+        balloon::Balloon,
+        block::{Block, DriveOptions},
+        net::{HostFwd, Net, NetBackendOptions, NetOptions},
+        p9::{Share, ShareOptions},
+        rng::Entropy,
+        vsock::{Vsock, VsockOptions},
+    },
+    ClockMode, ConsoleBackend, ExitReason, RtcStart, VmBuilder,
 };
+use std::{path::PathBuf, process::ExitCode};
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -30,290 +28,174 @@ struct Args {
     #[clap(short, long, default_value_t = 128)]
     memory: u32,
 
+    /// number of cpus, defaults to the number of host cpus or 1 with a
+    /// virtual clock
+    #[clap(long)]
+    cpus: Option<u32>,
+
     /// enable debug info
     #[clap(short, long)]
     debug: bool,
-}
-
-#[derive(Clone)]
-struct State {
-    memory: SharedMemory,
-    devicetree: Vec<u8>,
-    time_origin: Instant,
-    instance_pre: Option<InstancePre<State>>,
-}
-
-fn add_imports(linker: &mut Linker<State>) -> Result<()> {
-    linker.func_wrap("kernel", "breakpoint", move || {
-        raise(Signal::SIGTRAP).unwrap();
-    })?;
-    linker.func_wrap("kernel", "halt", || {
-        println!("halt");
-        // TODO: this should just kill this thread
-        std::process::exit(1);
-    })?;
-    linker.func_wrap("kernel", "restart", || {
-        println!("restart");
-        std::process::exit(1);
-    })?;
-
-    linker.func_wrap(
-        "kernel",
-        "boot_console_write",
-        |mut caller: Caller<'_, State>, msg: u32, len: u32| {
-            let State { memory, .. } = caller.data_mut();
-
-            let msg = msg as usize;
-            let len = len as usize;
-
-            let slice = &memory.data()[msg..][..len];
-            let slice = unsafe {
-                &slice
-                    .iter()
-                    .map(|cell| {
-                        *cell
-                            .get()
-                            .as_ref()
-                            .expect("wasm memory is not a null pointer")
-                    })
-                    .collect::<Vec<_>>()
-            };
-            stdout().write_all(slice)?;
-            Ok(())
-        },
-    )?;
-    linker.func_wrap("kernel", "boot_console_close", || {
-        println!("console closed");
-    })?;
-
-    linker.func_wrap("kernel", "return_address", |_frames: i32| -1)?;
-
-    linker.func_wrap(
-        "boot",
-        "get_devicetree",
-        |mut caller: Caller<'_, State>, buf: u32, len: u32| {
-            let State {
-                ref mut memory,
-                devicetree,
-                ..
-            } = caller.data_mut();
-            let memory = memory.data();
-            let buf = buf as usize;
-            let len = (len as usize).min(devicetree.len());
-            for i in 0..len {
-                unsafe {
-                    *memory[buf + i].get() = devicetree[i];
-                }
-            }
-        },
-    )?;
-    linker.func_wrap("kernel", "get_now_nsec", |caller: Caller<'_, State>| {
-        let duration = Instant::now() - caller.data().time_origin;
-        u64::try_from(duration.as_nanos())
-            .expect("584 years would have to pass for this to overflow")
-    })?;
-    linker.func_wrap(
-        "kernel",
-        "get_stacktrace",
-        |mut caller: Caller<'_, State>, buf: u32, len: u32| {
-            let memory = caller.data_mut().memory.data();
-
-            let trace = b"stack traces are unsupported";
-
-            let buf = buf as usize;
-            let len = (len as usize).min(trace.len());
-            for i in 0..len {
-                unsafe {
-                    *memory[buf..][i].get() = trace[i];
-                }
-            }
-        },
-    )?;
-
-    linker.func_wrap(
-        "kernel",
-        "new_worker",
-        |mut caller: Caller<'_, State>, task: u32, comm: u32, comm_len: u32| {
-            let memory = caller.data_mut().memory.data();
-            let comm = comm as usize;
-            let comm_len = comm_len as usize;
-            let mut name = Vec::with_capacity(comm_len);
 
-            for i in 0..comm_len {
-                unsafe {
-                    name.push(*memory[comm + i].get());
-                }
-            }
-
-            let data = caller.data().clone();
-            let instance_pre = data
-                .instance_pre
-                .clone()
-                .expect("instance_pre is intialized before the first call");
-            let engine = caller.engine().clone();
-
-            let name = String::from_utf8_lossy(&name).into_owned();
-            std::thread::Builder::new()
-                .name(name.clone())
-                .spawn(move || {
-                    let mut store = Store::new(&engine, data);
-
-                    handle_result(
-                        instance_pre
-                            .instantiate(&mut store)
-                            .unwrap()
-                            .get_typed_func::<u32, ()>(&mut store, "task")
-                            .expect("the function exists")
-                            .call(&mut store, task),
-                        &name,
-                        &mut store,
-                    );
-                })?;
-
-            Ok(())
-        },
-    )?;
-    linker.func_wrap(
-        "kernel",
-        "bringup_secondary",
-        |caller: Caller<'_, State>, cpu: u32, idle: u32| {
-            let data = caller.data().clone();
-            let instance_pre = data
-                .instance_pre
-                .clone()
-                .expect("instance_pre is intialized before the first call");
-            let engine = caller.engine().clone();
-
-            let name = format!("entry{cpu}");
-            std::thread::Builder::new()
-                .name(name.clone())
-                .spawn(move || {
-                    let mut store = Store::new(&engine, data);
-
-                    handle_result(
-                        instance_pre
-                            .instantiate(&mut store)
-                            .unwrap()
-                            .get_typed_func::<(u32, u32), ()>(&mut store, "secondary")
-                            .expect("the function exists")
-                            .call(&mut store, (cpu, idle)),
-                        &name,
-                        &mut store,
-                    );
-                })?;
-
-            Ok(())
-        },
-    )?;
-
-    Ok(())
+    /// path to an initramfs cpio archive
+    #[clap(long, conflicts_with = "initramfs_dir")]
+    initrd: Option<PathBuf>,
+
+    /// build the initramfs from a host directory
+    #[clap(long)]
+    initramfs_dir: Option<PathBuf>,
+
+    /// gen_init_cpio style manifest with extra initramfs entries like device nodes
+    #[clap(long, requires = "initramfs_dir")]
+    initramfs_manifest: Option<PathBuf>,
+
+    /// where to connect the console: stdio, pty or unix:<path>
+    #[clap(long, default_value = "stdio")]
+    console: ConsoleBackend,
+
+    /// also write the console output to this file
+    #[clap(long)]
+    console_log: Option<PathBuf>,
+
+    /// prefix each line in the console log with the time since boot
+    #[clap(long, requires = "console_log")]
+    console_log_timestamps: bool,
+
+    /// add a virtio-blk disk: file=<img>[,readonly=<bool>][,overlay=<file>|memory]
+    #[clap(long)]
+    drive: Vec<DriveOptions>,
+
+    /// add a virtio-net device: unix=<path>,peer=<path>|hub=<name>|user[,mac=<mac>][,pcap=<file>]
+    #[clap(long)]
+    net: Vec<NetOptions>,
+
+    /// forward a host port to the guest on the user network:
+    /// tcp:[<host addr>]:<host port>-[<guest addr>]:<guest port>
+    #[clap(long)]
+    hostfwd: Vec<HostFwd>,
+
+    /// share a host directory over virtio-9p: <tag>=<dir>[,ro],
+    /// mount it in the guest with `mount -t 9p -o trans=virtio <tag> <dir>`
+    #[clap(long)]
+    share: Vec<ShareOptions>,
+
+    /// add a virtio-rng device fed from host entropy
+    #[clap(long)]
+    rng: bool,
+
+    /// make the virtio-rng device and the devicetree's rng-seed deterministic
+    #[clap(long)]
+    rng_seed: Option<u64>,
+
+    /// add a virtio-vsock device bridged to host unix sockets: <uds_path>[,cid=<cid>]
+    #[clap(long)]
+    vsock: Option<VsockOptions>,
+
+    /// add a virtio-balloon device that returns free guest memory to the host,
+    /// optionally asking the guest for <MiB> of its memory right away
+    #[clap(long, value_name = "MiB", num_args = 0..=1, default_missing_value = "0")]
+    balloon: Option<u64>,
+
+    /// start the guest's wall clock at a fixed time instead of the host's:
+    /// <yyyy>-<mm>-<dd>[T<hh>:<mm>:<ss>] in utc or @<seconds since the epoch>
+    #[clap(long)]
+    rtc_start: Option<RtcStart>,
+
+    /// how the guest's clocks advance: host-real, scaled=<guest ns per host ns>
+    /// or virtual, one nanosecond per executed instruction on a single cpu
+    #[clap(long, default_value = "host-real")]
+    clock: ClockMode,
+
+    /// exit when the guest restarts instead of rebooting it
+    #[clap(long)]
+    no_reboot: bool,
 }
 
-type Sections = HashMap<String, (u32, u32)>;
-
-fn create_devicetree(cmdline: &str, sections: &Sections, memory_bytes: u32) -> Result<Vec<u8>> {
-    let mut fdt = FdtWriter::new()?;
-    let mut rng_seed = [0u64; 8];
-    rand::thread_rng().fill(&mut rng_seed);
-
-    let root = fdt.begin_node("root")?;
-
-    fdt.property_u32("#address-cells", 1)?;
-    fdt.property_u32("#size-cells", 1)?;
-
-    let chosen = fdt.begin_node("chosen")?;
-    fdt.property_array_u64("rng-seed", &rng_seed)?;
-    fdt.property_string("bootargs", cmdline)?;
-    fdt.property_u32("ncpus", num_cpus::get() as u32)?;
-
-    let data_sections = fdt.begin_node("sections")?;
-    for (name, &(start, end)) in sections {
-        fdt.property_array_u32(name, &[start, end])?;
+fn exit_code(reason: &ExitReason) -> ExitCode {
+    match reason {
+        ExitReason::PowerOff => ExitCode::SUCCESS,
+        ExitReason::Trap { .. } => ExitCode::from(1),
+        ExitReason::Panic { .. } => ExitCode::from(2),
+        ExitReason::Reboot => ExitCode::from(3),
+        ExitReason::Quit => ExitCode::from(4),
     }
-    fdt.end_node(data_sections)?;
-
-    fdt.end_node(chosen)?;
-
-    let aliases = fdt.begin_node("aliases")?;
-    fdt.end_node(aliases)?;
-
-    let memory = fdt.begin_node("memory")?;
-    fdt.property_string("device_type", "memory")?;
-    fdt.property_array_u32("reg", &[0, memory_bytes])?;
-    fdt.end_node(memory)?;
-
-    fdt.end_node(root)?;
-
-    Ok(fdt.finish()?)
 }
 
-fn handle_result(result: Result<()>, name: &str, store: &mut Store<State>) {
-    let Err(err) = result else {
-        return;
-    };
-
-    if let Some(dump) = err.downcast_ref::<WasmCoreDump>() {
-        let core = dump.serialize(store, name);
-        if let Err(err) = std::fs::write("kernel.coredump", core) {
-            eprintln!("while writing coredump: {err}");
-        }
+fn main() -> Result<ExitCode> {
+    let mut args = Args::parse();
+
+    if !args.hostfwd.is_empty() {
+        let Some(hostfwd) = args.net.iter_mut().find_map(|net| match &mut net.backend {
+            NetBackendOptions::User { hostfwd } => Some(hostfwd),
+            _ => None,
+        }) else {
+            bail!("--hostfwd needs a --net user network");
+        };
+        hostfwd.append(&mut args.hostfwd);
     }
 
-    eprintln!("in {name}: {err}");
-    std::process::exit(1);
-}
-
-fn main() -> Result<()> {
-    let args = Args::parse();
-
-    let sections: Sections = serde_json::from_reader(File::open(&args.sections)?)?;
-
-    let mut config = Config::new();
-    if args.debug {
-        config.debug_info(true);
-        config.native_unwind_info(true);
-        config.wasm_backtrace_details(WasmBacktraceDetails::Enable);
-        config.coredump_on_trap(true);
-        config.cranelift_opt_level(wasmtime::OptLevel::None);
+    let mut builder = VmBuilder::from_file(args.module)
+        .sections_file(args.sections)?
+        .cmdline(args.cmdline)
+        .memory(args.memory)
+        .debug(args.debug)
+        .reboot(!args.no_reboot)
+        .clock(args.clock)
+        .interactive(true)
+        .console(args.console);
+    if let Some(initrd) = args.initrd {
+        builder = builder.initrd_file(initrd)?;
+    }
+    if let Some(dir) = args.initramfs_dir {
+        builder = builder.initramfs_dir(dir, args.initramfs_manifest.as_deref())?;
+    }
+    if let Some(console_log) = args.console_log {
+        builder = builder.console_log(console_log, args.console_log_timestamps);
+    }
+    for drive in &args.drive {
+        builder = builder.virtio_device(Block::open(drive)?);
+    }
+    for net in &args.net {
+        builder = builder.virtio_device(Net::open(net)?);
+    }
+    for share in &args.share {
+        builder = builder.virtio_device(Share::new(share)?);
+    }
+    if let Some(balloon) = args.balloon {
+        let memory = u64::from(args.memory) << 20;
+        let Some(target) = balloon
+            .checked_mul(1 << 20)
+            .filter(|&target| target <= memory)
+        else {
+            bail!("--balloon {balloon} is more than --memory {}", args.memory);
+        };
+        builder = builder.virtio_device(Balloon::new(target)?);
+    }
+    if let Some(vsock) = &args.vsock {
+        builder = builder.virtio_device(Vsock::new(vsock)?);
+    }
+    match args.rng_seed {
+        Some(seed) => builder = builder.rng_seed(seed).virtio_device(Entropy::seeded(seed)),
+        None if args.rng => builder = builder.virtio_device(Entropy::new()),
+        None => {}
+    }
+    if let Some(RtcStart(start)) = args.rtc_start {
+        builder = builder.rtc_start(start);
+    }
+    match args.cpus {
+        Some(cpus) => builder = builder.cpus(cpus),
+        None if matches!(args.clock, ClockMode::Virtual) => builder = builder.cpus(1),
+        None => {}
     }
-    let engine = Engine::new(&config)?;
-
-    const PAGES_PER_MIB: u32 = 16;
-    const BYTES_PER_MIB: u32 = 0x100000;
-    let memory_pages = args.memory * PAGES_PER_MIB;
-    let memory_bytes = args.memory * BYTES_PER_MIB;
-
-    let memory = SharedMemory::new(&engine, MemoryType::shared(memory_pages, memory_pages))?;
-    debug_assert_eq!(memory.data_size(), memory_bytes as usize);
-
-    let module = Module::from_file(&engine, &args.module)?;
-
-    let mut store = Store::new(
-        &engine,
-        State {
-            memory: memory.clone(),
-            devicetree: create_devicetree(&args.cmdline, &sections, memory_bytes)?,
-            time_origin: Instant::now(),
-            instance_pre: None,
-        },
-    );
-
-    let mut linker = Linker::new(&engine);
-    add_imports(&mut linker)?;
-    linker.define(&store, "env", "memory", memory)?;
-
-    let instance_pre = linker.instantiate_pre(&module)?;
-    store.data_mut().instance_pre = Some(instance_pre.clone());
 
-    handle_result(
-        instance_pre
-            .instantiate(&mut store)?
-            .get_typed_func::<(), ()>(&mut store, "boot")
-            .expect("the function exists")
-            .call(&mut store, ()),
-        "boot",
-        &mut store,
-    );
+    let vm = builder.build()?;
+    if let Some(pty) = vm.console_pty() {
+        eprintln!("console on {}", pty.display());
+    }
+    let reason = vm.wait()?;
+    if !matches!(reason, ExitReason::PowerOff | ExitReason::Quit) {
+        eprintln!("{reason}");
+    }
 
-    Ok(())
+    Ok(exit_code(&reason))
 }
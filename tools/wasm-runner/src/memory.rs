@@ -0,0 +1,176 @@
+use crate::devicetree::Sections;
+use anyhow::{bail, Context, Result};
+use nix::{
+    sys::mman::{madvise, MmapAdvise},
+    unistd::{sysconf, SysconfVar},
+};
+use std::{
+    ptr::NonNull,
+    sync::{atomic::AtomicU32, OnceLock},
+    time::Duration,
+};
+use wasmtime::{SharedMemory, WaitResult};
+
+/// alignment of regions the runner places into guest memory
+const REGION_ALIGN: u32 = 0x10000;
+
+fn host_page_size() -> u64 {
+    static PAGE_SIZE: OnceLock<u64> = OnceLock::new();
+    *PAGE_SIZE.get_or_init(|| {
+        sysconf(SysconfVar::PAGE_SIZE)
+            .ok()
+            .flatten()
+            .map_or(4096, |size| size as u64)
+    })
+}
+
+/// hands out regions from the top of memory, above every kernel section
+pub(crate) struct Layout {
+    sections_end: u32,
+    top: u32,
+}
+
+impl Layout {
+    pub(crate) fn new(sections: &Sections, memory_bytes: u32) -> Self {
+        Self {
+            sections_end: sections.values().map(|&(_, end)| end).max().unwrap_or(0),
+            top: memory_bytes,
+        }
+    }
+
+    /// start of the lowest region handed out so far
+    pub(crate) fn top(&self) -> u32 {
+        self.top
+    }
+
+    /// find room for `len` bytes below the regions handed out so far
+    pub(crate) fn place(&mut self, len: u32) -> Result<u32> {
+        let Some(start) = self.top.checked_sub(len) else {
+            bail!("{len} bytes do not fit into memory");
+        };
+        let start = start & !(REGION_ALIGN - 1);
+
+        if start < self.sections_end {
+            bail!("{len} bytes do not fit between the kernel sections and the end of memory");
+        }
+
+        self.top = start;
+        Ok(start)
+    }
+}
+
+/// bounds checked access to guest memory for device models
+///
+/// the guest may modify memory concurrently, so every access copies.
+#[derive(Clone)]
+pub struct GuestMemory {
+    memory: SharedMemory,
+}
+
+impl GuestMemory {
+    pub(crate) fn new(memory: SharedMemory) -> Self {
+        Self { memory }
+    }
+
+    fn ptr(&self, addr: u64, len: usize) -> Result<*mut u8> {
+        let data = self.memory.data();
+        let start = usize::try_from(addr)?;
+        let in_bounds = start.checked_add(len).is_some_and(|end| end <= data.len());
+        if !in_bounds {
+            bail!("guest address {addr:#x}+{len:#x} is out of bounds");
+        }
+        Ok(data[start..].as_ptr() as *mut u8)
+    }
+
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let ptr = self.ptr(addr, buf.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    pub fn write(&self, addr: u64, bytes: &[u8]) -> Result<()> {
+        let ptr = self.ptr(addr, bytes.len())?;
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+        Ok(())
+    }
+
+    pub fn read_u16(&self, addr: u64) -> Result<u16> {
+        let mut buf = [0; 2];
+        self.read(addr, &mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Result<u32> {
+        let mut buf = [0; 4];
+        self.read(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Result<u64> {
+        let mut buf = [0; 8];
+        self.read(addr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn write_u16(&self, addr: u64, value: u16) -> Result<()> {
+        self.write(addr, &value.to_le_bytes())
+    }
+
+    pub fn write_u32(&self, addr: u64, value: u32) -> Result<()> {
+        self.write(addr, &value.to_le_bytes())
+    }
+
+    pub fn write_u64(&self, addr: u64, value: u64) -> Result<()> {
+        self.write(addr, &value.to_le_bytes())
+    }
+
+    /// a word the guest also accesses with atomic instructions
+    pub(crate) fn atomic_u32(&self, addr: u64) -> Result<&AtomicU32> {
+        if addr & 3 != 0 {
+            bail!("guest address {addr:#x} is not aligned");
+        }
+        let ptr = self.ptr(addr, 4)?;
+        Ok(unsafe { &*(ptr as *const AtomicU32) })
+    }
+
+    /// give the host pages in `addr..addr + len` back, they read as zero afterwards
+    ///
+    /// only whole host pages inside the range are dropped.
+    pub(crate) fn discard(&self, addr: u64, len: u64) -> Result<()> {
+        let page_size = host_page_size();
+        let start = addr.next_multiple_of(page_size);
+        let end = addr.saturating_add(len) & !(page_size - 1);
+        if start >= end {
+            return Ok(());
+        }
+        let len = usize::try_from(end - start)?;
+        let ptr = self.ptr(start, len)?;
+        // every vcpu thread maps the same pages, so they all see the zeros
+        unsafe {
+            madvise(
+                NonNull::new(ptr.cast()).context("null guest memory")?,
+                len,
+                MmapAdvise::MADV_DONTNEED,
+            )
+        }
+        .with_context(|| format!("while discarding guest memory at {start:#x}+{len:#x}"))
+    }
+
+    /// block like `memory.atomic.wait32` while `addr` holds `expected`,
+    /// returns false if `timeout` passed
+    pub(crate) fn wait(&self, addr: u64, expected: u32, timeout: Option<Duration>) -> Result<bool> {
+        let result = self
+            .memory
+            .atomic_wait32(addr, expected, timeout)
+            .context("while waiting on guest memory")?;
+        Ok(!matches!(result, WaitResult::TimedOut))
+    }
+
+    /// wake guest threads in `memory.atomic.wait32` on `addr`
+    pub(crate) fn wake(&self, addr: u64) -> Result<()> {
+        self.memory
+            .atomic_notify(addr, u32::MAX)
+            .context("while waking guest waiters")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! one-shot timers, one per cpu, for a tickless clockevent device
+//!
+//! the guest arms a cpu's timer with an absolute deadline on the
+//! `kernel.get_now_nsec` clock. when it passes, the timer irq is sent to that
+//! cpu and the timer is disarmed.
+
+use crate::{clock::Clock, irq::InterruptController, lifecycle::Lifecycle};
+use anyhow::{bail, Result};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+pub(crate) const TIMER_IRQ: u32 = 0;
+
+/// how long the timer thread sleeps without a deadline, or until a virtual
+/// clock advances, when nothing else wakes it
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+pub(crate) struct Timers {
+    clock: Arc<Clock>,
+    /// the deadline of every cpu's timer
+    deadlines: Mutex<Vec<Option<u64>>>,
+    changed: Condvar,
+}
+
+impl Timers {
+    /// start the thread that fires the timers of `ncpus` cpus
+    pub(crate) fn start(
+        ncpus: u32,
+        clock: Arc<Clock>,
+        interrupts: Arc<InterruptController>,
+        lifecycle: Arc<Lifecycle>,
+    ) -> Result<Arc<Self>> {
+        let timers = Arc::new(Self {
+            clock: clock.clone(),
+            deadlines: Mutex::new(vec![None; ncpus as usize]),
+            changed: Condvar::new(),
+        });
+
+        let woken = Arc::downgrade(&timers);
+        let wake = move || {
+            if let Some(timers) = woken.upgrade() {
+                let _deadlines = timers.deadlines.lock().unwrap();
+                timers.changed.notify_one();
+            }
+        };
+        clock.on_advance(wake.clone());
+        lifecycle.on_stop(wake);
+
+        let thread = timers.clone();
+        let stopped = lifecycle.clone();
+        let handle = std::thread::Builder::new()
+            .name(String::from("timer"))
+            .spawn(move || {
+                while !stopped.is_stopped() {
+                    if let Err(err) = thread.fire(&interrupts) {
+                        eprintln!("timer: {err:#}");
+                        return;
+                    }
+                }
+            })?;
+        lifecycle.track(handle);
+        Ok(timers)
+    }
+
+    /// wait for the next deadline and send the irqs of every expired timer
+    fn fire(&self, interrupts: &InterruptController) -> Result<()> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let now = self.clock.now();
+        let mut next = None::<u64>;
+        for (cpu, deadline) in deadlines.iter_mut().enumerate() {
+            match *deadline {
+                Some(at) if at <= now => {
+                    *deadline = None;
+                    interrupts.send(TIMER_IRQ, cpu as u32)?;
+                }
+                Some(at) => next = Some(next.map_or(at, |next| next.min(at))),
+                None => {}
+            }
+        }
+
+        let timeout = next
+            .and_then(|next| self.clock.host_duration(next))
+            .unwrap_or(IDLE_WAIT);
+        drop(self.changed.wait_timeout(deadlines, timeout).unwrap());
+        Ok(())
+    }
+
+    /// fire the timer irq on `cpu` once the clock reaches `deadline`
+    pub(crate) fn arm(&self, cpu: u32, deadline: u64) -> Result<()> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let Some(timer) = deadlines.get_mut(cpu as usize) else {
+            bail!("cpu {cpu} does not exist");
+        };
+        *timer = Some(deadline);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// when the timer of `cpu` fires, if it is armed
+    pub(crate) fn deadline(&self, cpu: u32) -> Option<u64> {
+        let deadlines = self.deadlines.lock().unwrap();
+        deadlines.get(cpu as usize).copied().flatten()
+    }
+
+    /// disarm the timer of `cpu`
+    pub(crate) fn cancel(&self, cpu: u32) -> Result<()> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let Some(timer) = deadlines.get_mut(cpu as usize) else {
+            bail!("cpu {cpu} does not exist");
+        };
+        *timer = None;
+        Ok(())
+    }
+}
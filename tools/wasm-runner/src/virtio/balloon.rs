@@ -0,0 +1,221 @@
+//! virtio-balloon, hands guest memory back to the host
+//!
+//! pages the guest puts into the balloon and free pages it reports are
+//! dropped with `MADV_DONTNEED`, the host only pays for memory the guest uses.
+
+use super::{Interrupt, Queue, VirtioDevice};
+use crate::memory::GuestMemory;
+use anyhow::{bail, Result};
+use std::sync::{Arc, Mutex};
+
+const VIRTIO_ID_BALLOON: u32 = 5;
+
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+const VIRTIO_BALLOON_F_REPORTING: u64 = 1 << 5;
+
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+const REPORTING_QUEUE: usize = 2;
+const QUEUE_SIZE: u16 = 256;
+
+/// the balloon counts in pages of this size, whatever the guest uses
+const BALLOON_PAGE_SIZE: u64 = 4096;
+
+struct Active {
+    memory: GuestMemory,
+    interrupt: Interrupt,
+    inflate: Queue,
+    deflate: Queue,
+    reporting: Queue,
+}
+
+#[derive(Default)]
+struct State {
+    /// pages the host wants in the balloon
+    target: u32,
+    /// pages the guest says are in the balloon
+    actual: u32,
+    interrupt: Option<Interrupt>,
+}
+
+impl State {
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.target.to_le_bytes().to_vec();
+        config.extend_from_slice(&self.actual.to_le_bytes());
+        config
+    }
+}
+
+/// changes the balloon size of a running vm
+#[derive(Clone)]
+pub struct BalloonControl {
+    state: Arc<Mutex<State>>,
+}
+
+impl BalloonControl {
+    /// ask the guest to give `bytes` of its memory to the host
+    pub fn set_target(&self, bytes: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.target = u32::try_from(bytes / BALLOON_PAGE_SIZE)?;
+        if let Some(interrupt) = &state.interrupt {
+            interrupt.signal_config(&state.config())?;
+        }
+        Ok(())
+    }
+
+    /// how many bytes the guest gave to the host so far
+    pub fn actual(&self) -> u64 {
+        u64::from(self.state.lock().unwrap().actual) * BALLOON_PAGE_SIZE
+    }
+}
+
+/// virtio-balloon with free page reporting
+pub struct Balloon {
+    state: Arc<Mutex<State>>,
+    active: Option<Active>,
+}
+
+impl Balloon {
+    /// a balloon that starts out asking for `target` bytes
+    pub fn new(target: u64) -> Result<Self> {
+        let balloon = Self {
+            state: Arc::default(),
+            active: None,
+        };
+        balloon.control().set_target(target)?;
+        Ok(balloon)
+    }
+
+    pub fn control(&self) -> BalloonControl {
+        BalloonControl {
+            state: self.state.clone(),
+        }
+    }
+
+    /// drop the pages whose numbers the guest put into the inflate queue
+    fn inflate(&mut self) -> Result<()> {
+        let Some(active) = &mut self.active else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while let Some(request) = active.inflate.pop(&active.memory)? {
+            let pfns = request.read_all(&active.memory)?;
+            for pfn in pfns.chunks_exact(4) {
+                let pfn = u64::from(u32::from_le_bytes(pfn.try_into()?));
+                active
+                    .memory
+                    .discard(pfn * BALLOON_PAGE_SIZE, BALLOON_PAGE_SIZE)?;
+            }
+            active.inflate.add_used(&active.memory, request.head, 0)?;
+            used = true;
+        }
+
+        if used {
+            active.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+
+    /// pages leaving the balloon fault back in as zeros, nothing to do
+    fn deflate(&mut self) -> Result<()> {
+        let Some(active) = &mut self.active else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while let Some(request) = active.deflate.pop(&active.memory)? {
+            active.deflate.add_used(&active.memory, request.head, 0)?;
+            used = true;
+        }
+
+        if used {
+            active.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+
+    /// drop the free ranges the guest reported, they stay the guest's
+    fn report(&mut self) -> Result<()> {
+        let Some(active) = &mut self.active else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while let Some(request) = active.reporting.pop(&active.memory)? {
+            for range in &request.descriptors {
+                active.memory.discard(range.addr, u64::from(range.len))?;
+            }
+            active.reporting.add_used(&active.memory, request.head, 0)?;
+            used = true;
+        }
+
+        if used {
+            active.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Balloon {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; 3]
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_BALLOON_F_DEFLATE_ON_OOM | VIRTIO_BALLOON_F_REPORTING
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.state.lock().unwrap().config()
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // the driver only writes `actual`
+        if let (4, Ok(actual)) = (offset, <[u8; 4]>::try_from(data)) {
+            self.state.lock().unwrap().actual = u32::from_le_bytes(actual);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        _features: u64,
+    ) -> Result<()> {
+        // without reporting the driver never readies the last queue
+        let Ok([inflate, deflate, reporting]) = <[Queue; 3]>::try_from(queues) else {
+            bail!("virtio-balloon needs an inflate, a deflate and a reporting queue");
+        };
+        self.state.lock().unwrap().interrupt = Some(interrupt.clone());
+        self.active = Some(Active {
+            memory,
+            interrupt,
+            inflate,
+            deflate,
+            reporting,
+        });
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, queue: usize) -> Result<()> {
+        match queue {
+            INFLATE_QUEUE => self.inflate(),
+            DEFLATE_QUEUE => self.deflate(),
+            REPORTING_QUEUE => self.report(),
+            _ => bail!("virtio-balloon has no queue {queue}"),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.active = None;
+        let mut state = self.state.lock().unwrap();
+        state.interrupt = None;
+        state.actual = 0;
+    }
+}
@@ -0,0 +1,608 @@
+use super::{overlay::Overlay, DescriptorChain, Interrupt, Queue, VirtioDevice};
+use crate::memory::GuestMemory;
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{fallocate, FallocateFlags};
+use std::{
+    fs::{File, OpenOptions},
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const SECTOR_SIZE: u64 = 512;
+const QUEUE_SIZE: u16 = 256;
+const ID_LEN: usize = 20;
+const REQUEST_HEADER_LEN: usize = 16;
+const DISCARD_SEGMENT_LEN: usize = 16;
+
+/// storage behind a block device
+pub trait Disk: Send {
+    /// size in bytes
+    fn size(&self) -> u64;
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()>;
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()>;
+    fn flush(&mut self) -> Result<()>;
+    /// the range is no longer used and reads back as zeroes
+    fn discard(&mut self, offset: u64, len: u64) -> Result<()>;
+}
+
+/// a raw disk image on the host
+pub struct RawDisk {
+    file: File,
+    size: u64,
+}
+
+impl RawDisk {
+    pub fn open(path: &Path, readonly: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!readonly)
+            .open(path)
+            .with_context(|| format!("while opening {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+}
+
+impl Disk for RawDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        Ok(self.file.read_exact_at(buf, offset)?)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        Ok(self.file.write_all_at(buf, offset)?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.file.sync_data()?)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> Result<()> {
+        fallocate(
+            self.file.as_raw_fd(),
+            FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            offset.try_into()?,
+            len.try_into()?,
+        )?;
+        Ok(())
+    }
+}
+
+/// where the writes to a drive with an overlay go
+#[derive(Clone, Debug)]
+pub enum OverlayTarget {
+    /// a sparse overlay file, recreated on every run
+    File(PathBuf),
+    /// kept in memory and discarded at exit
+    Memory,
+}
+
+/// the value of a `--drive` option,
+/// `file=<img>[,readonly=<bool>][,overlay=<file>|memory]`
+#[derive(Clone, Debug)]
+pub struct DriveOptions {
+    pub file: PathBuf,
+    pub readonly: bool,
+    /// leave the image untouched and send writes here
+    pub overlay: Option<OverlayTarget>,
+}
+
+impl FromStr for DriveOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(options: &str) -> Result<Self> {
+        let mut file = None;
+        let mut readonly = false;
+        let mut overlay = None;
+        for option in options.split(',') {
+            match option.split_once('=') {
+                Some(("file", path)) => file = Some(PathBuf::from(path)),
+                Some(("readonly", value)) => {
+                    readonly = match value {
+                        "on" | "true" | "yes" => true,
+                        "off" | "false" | "no" => false,
+                        _ => bail!("expected a boolean for readonly, got {value}"),
+                    }
+                }
+                Some(("overlay", "memory")) => overlay = Some(OverlayTarget::Memory),
+                Some(("overlay", path)) => overlay = Some(OverlayTarget::File(path.into())),
+                _ => bail!("unknown drive option {option}"),
+            }
+        }
+        let Some(file) = file else {
+            bail!("a drive needs a file");
+        };
+        if readonly && overlay.is_some() {
+            bail!("a readonly drive can not have an overlay");
+        }
+        Ok(Self {
+            file,
+            readonly,
+            overlay,
+        })
+    }
+}
+
+struct Active {
+    memory: GuestMemory,
+    interrupt: Interrupt,
+    queue: Queue,
+}
+
+/// virtio-blk with a single request queue, requests complete synchronously
+pub struct Block {
+    disk: Box<dyn Disk>,
+    readonly: bool,
+    id: Vec<u8>,
+    active: Option<Active>,
+}
+
+impl Block {
+    pub fn new(disk: impl Disk + 'static, readonly: bool, id: &str) -> Self {
+        Self::with_disk(Box::new(disk), readonly, id)
+    }
+
+    fn with_disk(disk: Box<dyn Disk>, readonly: bool, id: &str) -> Self {
+        let mut id = id.as_bytes().to_vec();
+        id.truncate(ID_LEN);
+        Self {
+            disk,
+            readonly,
+            id,
+            active: None,
+        }
+    }
+
+    /// a device for the image in `options`
+    pub fn open(options: &DriveOptions) -> Result<Self> {
+        let disk: Box<dyn Disk> = match &options.overlay {
+            None => Box::new(RawDisk::open(&options.file, options.readonly)?),
+            Some(OverlayTarget::File(path)) => Box::new(Overlay::create(&options.file, path)?),
+            Some(OverlayTarget::Memory) => Box::new(Overlay::in_memory(&options.file)?),
+        };
+        let id = options
+            .file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Self::with_disk(disk, options.readonly, &id))
+    }
+
+    fn sectors(&self) -> u64 {
+        self.disk.size() / SECTOR_SIZE
+    }
+
+    /// check that `len` bytes at `sector` are on the disk, returns the byte offset
+    fn offset(&self, sector: u64, len: u64) -> Result<u64> {
+        let offset = sector
+            .checked_mul(SECTOR_SIZE)
+            .context("sector overflows")?;
+        match offset.checked_add(len) {
+            Some(end) if end <= self.sectors() * SECTOR_SIZE => Ok(offset),
+            _ => bail!("access to sector {sector} beyond the end of the disk"),
+        }
+    }
+
+    /// run the request, returns the status and how many bytes went to the driver
+    fn execute(
+        &mut self,
+        memory: &GuestMemory,
+        request: &DescriptorChain,
+        data: &[Data],
+    ) -> Result<(u8, u32)> {
+        let readable = request.read_all(memory)?;
+        let Some((header, payload)) = readable.split_at_checked(REQUEST_HEADER_LEN) else {
+            bail!("request header is too short");
+        };
+        let kind = u32::from_le_bytes(header[0..4].try_into()?);
+        let sector = u64::from_le_bytes(header[8..16].try_into()?);
+        let data_len: u64 = data.iter().map(|data| u64::from(data.len)).sum();
+
+        match kind {
+            VIRTIO_BLK_T_IN => {
+                let offset = self.offset(sector, data_len)?;
+                let mut buf = vec![0; data_len as usize];
+                self.disk.read_at(&mut buf, offset)?;
+                Ok((VIRTIO_BLK_S_OK, write_data(memory, data, &buf)?))
+            }
+            // the driver knows from VIRTIO_BLK_F_RO, writing anyway is an error
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD if self.readonly => Ok((VIRTIO_BLK_S_IOERR, 0)),
+            VIRTIO_BLK_T_OUT => {
+                let offset = self.offset(sector, payload.len() as u64)?;
+                self.disk.write_at(payload, offset)?;
+                Ok((VIRTIO_BLK_S_OK, 0))
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                self.disk.flush()?;
+                Ok((VIRTIO_BLK_S_OK, 0))
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = self.id.clone();
+                id.resize(ID_LEN, 0);
+                Ok((VIRTIO_BLK_S_OK, write_data(memory, data, &id)?))
+            }
+            VIRTIO_BLK_T_DISCARD => {
+                for segment in payload.chunks_exact(DISCARD_SEGMENT_LEN) {
+                    let sector = u64::from_le_bytes(segment[0..8].try_into()?);
+                    let sectors = u32::from_le_bytes(segment[8..12].try_into()?);
+                    let len = u64::from(sectors) * SECTOR_SIZE;
+                    let offset = self.offset(sector, len)?;
+                    self.disk.discard(offset, len)?;
+                }
+                Ok((VIRTIO_BLK_S_OK, 0))
+            }
+            _ => Ok((VIRTIO_BLK_S_UNSUPP, 0)),
+        }
+    }
+
+    fn process_queue(&mut self) -> Result<()> {
+        let Some(mut active) = self.active.take() else {
+            return Ok(());
+        };
+        let result = self.process_requests(&mut active);
+        self.active = Some(active);
+        result
+    }
+
+    fn process_requests(&mut self, active: &mut Active) -> Result<()> {
+        let mut used = false;
+        while let Some(request) = active.queue.pop(&active.memory)? {
+            // the last writable byte is the status, everything before it is data
+            let mut data = request
+                .writable()
+                .map(|descriptor| Data {
+                    addr: descriptor.addr,
+                    len: descriptor.len,
+                })
+                .collect::<Vec<_>>();
+            let Some(status) = data.last_mut().filter(|status| status.len > 0) else {
+                bail!("request without a status byte");
+            };
+            status.len -= 1;
+            let status_addr = status.addr + u64::from(status.len);
+
+            let (status, len) = match self.execute(&active.memory, &request, &data) {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("virtio-blk: {err:#}");
+                    (VIRTIO_BLK_S_IOERR, 0)
+                }
+            };
+            active.memory.write(status_addr, &[status])?;
+            active
+                .queue
+                .add_used(&active.memory, request.head, len + 1)?;
+            used = true;
+        }
+
+        if used {
+            active.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+}
+
+/// a device writable buffer
+struct Data {
+    addr: u64,
+    len: u32,
+}
+
+/// spread `bytes` over `data`, returns how many bytes fit
+fn write_data(memory: &GuestMemory, data: &[Data], mut bytes: &[u8]) -> Result<u32> {
+    let mut written = 0;
+    for data in data {
+        let len = bytes.len().min(data.len as usize);
+        memory.write(data.addr, &bytes[..len])?;
+        bytes = &bytes[len..];
+        written += len as u32;
+    }
+    Ok(written)
+}
+
+impl VirtioDevice for Block {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        let features = VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        if self.readonly {
+            features | VIRTIO_BLK_F_RO
+        } else {
+            features | VIRTIO_BLK_F_DISCARD
+        }
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 60];
+        config[0..8].copy_from_slice(&self.sectors().to_le_bytes());
+        // seg_max, the header and status take two descriptors
+        config[12..16].copy_from_slice(&u32::from(QUEUE_SIZE - 2).to_le_bytes());
+        config[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        // max_discard_sectors, max_discard_seg and discard_sector_alignment
+        config[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+        config[40..44].copy_from_slice(&1u32.to_le_bytes());
+        config[44..48].copy_from_slice(&1u32.to_le_bytes());
+        config
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        _features: u64,
+    ) -> Result<()> {
+        let Some(queue) = queues.pop() else {
+            bail!("virtio-blk needs a request queue");
+        };
+        self.active = Some(Active {
+            memory,
+            interrupt,
+            queue,
+        });
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, _queue: usize) -> Result<()> {
+        self.process_queue()
+    }
+
+    fn reset(&mut self) {
+        self.active = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::TempDir, virtio::Descriptor};
+    use wasmtime::{Config, Engine, MemoryType, SharedMemory};
+
+    const REQUEST: u64 = 0x1000;
+    const DATA: u64 = 0x8000;
+    const SECTORS: u64 = 4;
+
+    fn drive(options: &str) -> Result<(bool, Option<OverlayTarget>)> {
+        let DriveOptions {
+            file,
+            readonly,
+            overlay,
+        } = options.parse()?;
+        assert_eq!(file, PathBuf::from("disk.img"));
+        Ok((readonly, overlay))
+    }
+
+    #[test]
+    fn drive_options() {
+        assert!(matches!(drive("file=disk.img").unwrap(), (false, None)));
+        for value in ["on", "true", "yes"] {
+            let options = format!("file=disk.img,readonly={value}");
+            assert!(matches!(drive(&options).unwrap(), (true, None)), "{value}");
+        }
+        for value in ["off", "false", "no"] {
+            let options = format!("readonly={value},file=disk.img");
+            assert!(matches!(drive(&options).unwrap(), (false, None)), "{value}");
+        }
+        assert!(matches!(
+            drive("file=disk.img,overlay=memory").unwrap(),
+            (false, Some(OverlayTarget::Memory))
+        ));
+        assert!(matches!(
+            drive("file=disk.img,overlay=cow.img").unwrap(),
+            (false, Some(OverlayTarget::File(path))) if path == Path::new("cow.img")
+        ));
+    }
+
+    #[test]
+    fn invalid_drive_options() {
+        for options in [
+            "",
+            "readonly=on",
+            "file=disk.img,readonly",
+            "file=disk.img,readonly=1",
+            "file=disk.img,readonly=maybe",
+            "file=disk.img,readonly=on,overlay=memory",
+            "file=disk.img,overlay=cow.img,readonly=yes",
+            "file=disk.img,cache=none",
+        ] {
+            assert!(options.parse::<DriveOptions>().is_err(), "{options}");
+        }
+    }
+
+    /// a raw image of `SECTORS` sectors, every one filled with its number
+    struct Fixture {
+        dir: TempDir,
+        memory: GuestMemory,
+        block: Block,
+    }
+
+    impl Fixture {
+        fn new(name: &str, readonly: bool) -> Self {
+            let dir = TempDir::new(&format!("block-{name}"));
+            let image = (0..SECTORS * SECTOR_SIZE)
+                .map(|at| (at / SECTOR_SIZE) as u8)
+                .collect::<Vec<_>>();
+            std::fs::write(dir.join("disk.img"), image).unwrap();
+
+            let mut config = Config::new();
+            config.wasm_threads(true);
+            let engine = Engine::new(&config).unwrap();
+            let memory =
+                GuestMemory::new(SharedMemory::new(&engine, MemoryType::shared(1, 1)).unwrap());
+            let disk = RawDisk::open(&dir.join("disk.img"), readonly).unwrap();
+            Self {
+                dir,
+                memory,
+                block: Block::new(disk, readonly, "disk.img"),
+            }
+        }
+
+        fn image(&self) -> Vec<u8> {
+            std::fs::read(self.dir.join("disk.img")).unwrap()
+        }
+
+        /// run a request with `payload` after the header and `data_len`
+        /// writable bytes, returns the status and the data written back
+        fn request(
+            &mut self,
+            kind: u32,
+            sector: u64,
+            payload: &[u8],
+            data_len: u32,
+        ) -> Result<(u8, Vec<u8>)> {
+            let mut readable = vec![0; REQUEST_HEADER_LEN];
+            readable[0..4].copy_from_slice(&kind.to_le_bytes());
+            readable[8..16].copy_from_slice(&sector.to_le_bytes());
+            readable.extend_from_slice(payload);
+            self.memory.write(REQUEST, &readable).unwrap();
+            self.memory
+                .write(DATA, &vec![0xee; data_len as usize])
+                .unwrap();
+
+            let request = DescriptorChain {
+                head: 0,
+                descriptors: vec![Descriptor {
+                    addr: REQUEST,
+                    len: readable.len() as u32,
+                    writable: false,
+                }],
+            };
+            let data = [Data {
+                addr: DATA,
+                len: data_len,
+            }];
+            let (status, len) = self.block.execute(&self.memory, &request, &data)?;
+            let mut written = vec![0; len as usize];
+            self.memory.read(DATA, &mut written).unwrap();
+            Ok((status, written))
+        }
+    }
+
+    fn discard_segment(sector: u64, sectors: u32) -> Vec<u8> {
+        let mut segment = sector.to_le_bytes().to_vec();
+        segment.extend_from_slice(&sectors.to_le_bytes());
+        // flags
+        segment.extend_from_slice(&[0; 4]);
+        segment
+    }
+
+    #[test]
+    fn requests() {
+        let mut fixture = Fixture::new("requests", false);
+        let features = fixture.block.features();
+        assert_eq!(features & VIRTIO_BLK_F_RO, 0);
+        assert_ne!(features & VIRTIO_BLK_F_DISCARD, 0);
+        let sector = SECTOR_SIZE as usize;
+
+        let (status, data) = fixture
+            .request(VIRTIO_BLK_T_IN, 1, &[], 2 * SECTOR_SIZE as u32)
+            .unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(data[..sector], [1; SECTOR_SIZE as usize]);
+        assert_eq!(data[sector..], [2; SECTOR_SIZE as usize]);
+
+        let (status, data) = fixture
+            .request(VIRTIO_BLK_T_OUT, 2, &[0xaa; SECTOR_SIZE as usize], 0)
+            .unwrap();
+        assert_eq!((status, data.len()), (VIRTIO_BLK_S_OK, 0));
+        assert_eq!(
+            fixture.image()[2 * sector..3 * sector],
+            [0xaa; SECTOR_SIZE as usize]
+        );
+
+        let (status, _) = fixture.request(VIRTIO_BLK_T_FLUSH, 0, &[], 0).unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+
+        let (status, data) = fixture
+            .request(VIRTIO_BLK_T_GET_ID, 0, &[], ID_LEN as u32)
+            .unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(&data[..8], b"disk.img");
+        assert_eq!(data[8..], [0; ID_LEN - 8]);
+
+        let (status, _) = fixture
+            .request(VIRTIO_BLK_T_DISCARD, 3, &discard_segment(3, 1), 0)
+            .unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        let image = fixture.image();
+        assert_eq!(image.len() as u64, SECTORS * SECTOR_SIZE);
+        assert_eq!(image[3 * sector..], [0; SECTOR_SIZE as usize]);
+        assert_eq!(image[..sector], [0; SECTOR_SIZE as usize]);
+        assert_eq!(image[sector..2 * sector], [1; SECTOR_SIZE as usize]);
+
+        let (status, _) = fixture.request(99, 0, &[], 0).unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_UNSUPP);
+    }
+
+    #[test]
+    fn requests_past_the_end() {
+        let mut fixture = Fixture::new("past-the-end", false);
+        let len = SECTOR_SIZE as u32;
+        assert!(fixture.request(VIRTIO_BLK_T_IN, SECTORS, &[], len).is_err());
+        assert!(fixture
+            .request(VIRTIO_BLK_T_IN, SECTORS - 1, &[], 2 * len)
+            .is_err());
+        assert!(fixture
+            .request(VIRTIO_BLK_T_OUT, SECTORS, &[0; SECTOR_SIZE as usize], 0)
+            .is_err());
+        assert!(fixture
+            .request(VIRTIO_BLK_T_DISCARD, 0, &discard_segment(SECTORS - 1, 2), 0)
+            .is_err());
+        assert!(fixture
+            .request(VIRTIO_BLK_T_IN, u64::MAX, &[], len)
+            .is_err());
+    }
+
+    #[test]
+    fn readonly_disks_fail_writes() {
+        let mut fixture = Fixture::new("readonly", true);
+        let features = fixture.block.features();
+        assert_ne!(features & VIRTIO_BLK_F_RO, 0);
+        assert_eq!(features & VIRTIO_BLK_F_DISCARD, 0);
+        let image = fixture.image();
+
+        let (status, _) = fixture
+            .request(VIRTIO_BLK_T_OUT, 0, &[0xaa; SECTOR_SIZE as usize], 0)
+            .unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        let (status, _) = fixture
+            .request(VIRTIO_BLK_T_DISCARD, 0, &discard_segment(0, 1), 0)
+            .unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        assert_eq!(fixture.image(), image);
+
+        let (status, data) = fixture
+            .request(VIRTIO_BLK_T_IN, 0, &[], SECTOR_SIZE as u32)
+            .unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(data, image[..SECTOR_SIZE as usize]);
+    }
+}
@@ -0,0 +1,387 @@
+//! virtio-mmio without trapping memory accesses
+//!
+//! every device has a register window in guest memory laid out like a
+//! version 2 virtio-mmio device. the runner keeps the read-only registers up
+//! to date, the guest writes the others directly and then calls the
+//! `kernel.virtio_mmio_notify` doorbell with the offset it wrote. writes to
+//! `QueueNum` and the queue addresses take effect once `QueueReady` is set.
+
+use super::{Queue, VirtioDevice, VIRTIO_F_VERSION_1, VIRTIO_RING_F_INDIRECT_DESC};
+use crate::{
+    irq::{InterruptController, FIRST_DEVICE_IRQ, NR_IRQS},
+    lifecycle::Lifecycle,
+    memory::GuestMemory,
+};
+use anyhow::{bail, Result};
+use std::sync::{atomic::Ordering, Arc, Mutex};
+
+pub(crate) const WINDOW_SIZE: u32 = 0x200;
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+const MAGIC: u32 = 0x7472_6976;
+/// "WASM"
+const VENDOR: u32 = 0x4d53_4157;
+
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_NEEDS_RESET: u32 = 64;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+pub(crate) type SharedDevice = Arc<Mutex<Box<dyn VirtioDevice>>>;
+
+/// lets a device signal the driver
+#[derive(Clone)]
+pub struct Interrupt {
+    memory: GuestMemory,
+    base: u64,
+    controller: Arc<InterruptController>,
+    irq: u32,
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl Interrupt {
+    fn raise(&self, status: u32) -> Result<()> {
+        self.memory
+            .atomic_u32(self.base + INTERRUPT_STATUS)?
+            .fetch_or(status, Ordering::SeqCst);
+        self.memory.wake(self.base + INTERRUPT_STATUS)?;
+        self.controller.raise(self.irq)
+    }
+
+    /// the device put buffers into a used ring
+    pub fn signal_used(&self) -> Result<()> {
+        self.raise(INTERRUPT_USED_BUFFER)
+    }
+
+    /// the device changed its configuration space to `config`
+    pub fn signal_config(&self, config: &[u8]) -> Result<()> {
+        self.memory.write(self.base + CONFIG, config)?;
+        self.memory
+            .atomic_u32(self.base + CONFIG_GENERATION)?
+            .fetch_add(1, Ordering::SeqCst);
+        self.raise(INTERRUPT_CONFIG_CHANGE)
+    }
+
+    /// the boot the driver runs in, threads serving it stop with it
+    pub(crate) fn lifecycle(&self) -> &Arc<Lifecycle> {
+        &self.lifecycle
+    }
+}
+
+struct MmioTransport {
+    memory: GuestMemory,
+    base: u64,
+    controller: Arc<InterruptController>,
+    irq: u32,
+    lifecycle: Arc<Lifecycle>,
+    device: SharedDevice,
+    queues: Vec<Queue>,
+    queue_sel: usize,
+    driver_features_sel: u32,
+    driver_features: u64,
+    activated: bool,
+}
+
+impl MmioTransport {
+    fn new(
+        memory: GuestMemory,
+        base: u64,
+        controller: Arc<InterruptController>,
+        irq: u32,
+        lifecycle: Arc<Lifecycle>,
+        device: SharedDevice,
+    ) -> Result<Self> {
+        let mut device_lock = device.lock().unwrap();
+        device_lock.reset();
+        let queues = device_lock
+            .queue_max_sizes()
+            .into_iter()
+            .map(Queue::new)
+            .collect();
+        let device_type = device_lock.device_type();
+        let config = device_lock.config();
+        drop(device_lock);
+
+        let transport = Self {
+            memory,
+            base,
+            controller,
+            irq,
+            lifecycle,
+            device,
+            queues,
+            queue_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            activated: false,
+        };
+        transport.write(MAGIC_VALUE, MAGIC)?;
+        transport.write(VERSION, 2)?;
+        transport.write(DEVICE_ID, device_type)?;
+        transport.write(VENDOR_ID, VENDOR)?;
+        transport.select_device_features(0)?;
+        transport.select_queue()?;
+        transport.memory.write(base + CONFIG, &config)?;
+
+        Ok(transport)
+    }
+
+    fn interrupt(&self) -> Interrupt {
+        Interrupt {
+            memory: self.memory.clone(),
+            base: self.base,
+            controller: self.controller.clone(),
+            irq: self.irq,
+            lifecycle: self.lifecycle.clone(),
+        }
+    }
+
+    fn read(&self, offset: u64) -> Result<u32> {
+        self.memory.read_u32(self.base + offset)
+    }
+
+    fn write(&self, offset: u64, value: u32) -> Result<()> {
+        self.memory.write_u32(self.base + offset, value)
+    }
+
+    fn read_u64(&self, offset: u64) -> Result<u64> {
+        Ok(u64::from(self.read(offset)?) | u64::from(self.read(offset + 4)?) << 32)
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) -> Result<()> {
+        self.write(offset, value as u32)?;
+        self.write(offset + 4, (value >> 32) as u32)
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.device.lock().unwrap().features() | VIRTIO_F_VERSION_1 | VIRTIO_RING_F_INDIRECT_DESC
+    }
+
+    fn select_device_features(&self, sel: u32) -> Result<()> {
+        let features = match sel {
+            0 => self.offered_features() as u32,
+            1 => (self.offered_features() >> 32) as u32,
+            _ => 0,
+        };
+        self.write(DEVICE_FEATURES, features)
+    }
+
+    /// show the selected queue's configuration in the window
+    fn select_queue(&self) -> Result<()> {
+        let Some(queue) = self.queues.get(self.queue_sel) else {
+            self.write(QUEUE_NUM_MAX, 0)?;
+            return self.write(QUEUE_READY, 0);
+        };
+        self.write(QUEUE_NUM_MAX, u32::from(queue.max_size))?;
+        self.write(QUEUE_NUM, u32::from(queue.size))?;
+        self.write(QUEUE_READY, u32::from(queue.ready))?;
+        self.write_u64(QUEUE_DESC_LOW, queue.desc_table)?;
+        self.write_u64(QUEUE_DRIVER_LOW, queue.avail_ring)?;
+        self.write_u64(QUEUE_DEVICE_LOW, queue.used_ring)
+    }
+
+    fn set_queue_ready(&mut self, ready: bool) -> Result<()> {
+        let size = self.read(QUEUE_NUM)?;
+        let desc_table = self.read_u64(QUEUE_DESC_LOW)?;
+        let avail_ring = self.read_u64(QUEUE_DRIVER_LOW)?;
+        let used_ring = self.read_u64(QUEUE_DEVICE_LOW)?;
+
+        let Some(queue) = self.queues.get_mut(self.queue_sel) else {
+            bail!("queue {} does not exist", self.queue_sel);
+        };
+        if ready && (size == 0 || size > u32::from(queue.max_size) || !size.is_power_of_two()) {
+            bail!("invalid size {size} for queue {}", self.queue_sel);
+        }
+        queue.size = size as u16;
+        queue.desc_table = desc_table;
+        queue.avail_ring = avail_ring;
+        queue.used_ring = used_ring;
+        queue.ready = ready;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.device.lock().unwrap().reset();
+        for queue in self.queues.iter_mut() {
+            *queue = Queue::new(queue.max_size);
+        }
+        self.queue_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.activated = false;
+        self.write(INTERRUPT_STATUS, 0)?;
+        self.controller.lower(self.irq)?;
+        self.select_queue()
+    }
+
+    fn set_status(&mut self, status: u32) -> Result<()> {
+        if status == 0 {
+            return self.reset();
+        }
+
+        if status & STATUS_FEATURES_OK != 0 {
+            let offered = self.offered_features();
+            if self.driver_features & !offered != 0
+                || self.driver_features & VIRTIO_F_VERSION_1 == 0
+            {
+                // the driver sees that the features were not accepted
+                self.write(STATUS, status & !STATUS_FEATURES_OK)?;
+                return Ok(());
+            }
+        }
+
+        if status & STATUS_DRIVER_OK != 0 && !self.activated {
+            self.device.lock().unwrap().activate(
+                self.memory.clone(),
+                self.interrupt(),
+                self.queues.clone(),
+                self.driver_features,
+            )?;
+            self.activated = true;
+        }
+
+        Ok(())
+    }
+
+    /// the guest wrote the register at `offset`
+    fn notify(&mut self, offset: u64) -> Result<()> {
+        match offset {
+            DEVICE_FEATURES_SEL => self.select_device_features(self.read(offset)?)?,
+            DRIVER_FEATURES_SEL => self.driver_features_sel = self.read(offset)?,
+            DRIVER_FEATURES => {
+                let value = u64::from(self.read(offset)?);
+                match self.driver_features_sel {
+                    0 => self.driver_features = self.driver_features & !0xffff_ffff | value,
+                    1 => self.driver_features = self.driver_features & 0xffff_ffff | value << 32,
+                    _ => {}
+                }
+            }
+            QUEUE_SEL => {
+                self.queue_sel = self.read(offset)? as usize;
+                self.select_queue()?;
+            }
+            QUEUE_READY => self.set_queue_ready(self.read(offset)? != 0)?,
+            QUEUE_NOTIFY => {
+                let queue = self.read(offset)? as usize;
+                if self.activated {
+                    self.device.lock().unwrap().queue_notify(queue)?;
+                }
+            }
+            INTERRUPT_ACK => {
+                let ack = self.read(offset)?;
+                let status = self
+                    .memory
+                    .atomic_u32(self.base + INTERRUPT_STATUS)?
+                    .fetch_and(!ack, Ordering::SeqCst);
+                if status & !ack == 0 {
+                    self.controller.lower(self.irq)?;
+                }
+            }
+            STATUS => self.set_status(self.read(offset)?)?,
+            CONFIG.. if offset < u64::from(WINDOW_SIZE) => {
+                let mut data = [0; 4];
+                self.memory.read(self.base + offset, &mut data)?;
+                let mut device = self.device.lock().unwrap();
+                device.write_config(offset - CONFIG, &data);
+                self.memory.write(self.base + CONFIG, &device.config())?;
+            }
+            // QueueNum and the queue addresses are read when the queue gets ready
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// the register windows of every virtio device, one after another
+pub(crate) struct VirtioMmio {
+    base: u64,
+    transports: Vec<Mutex<MmioTransport>>,
+}
+
+impl VirtioMmio {
+    /// reset `devices` and put their windows at `base`, each gets its own irq line
+    pub(crate) fn new(
+        memory: &GuestMemory,
+        base: u32,
+        controller: &Arc<InterruptController>,
+        lifecycle: &Arc<Lifecycle>,
+        devices: &[SharedDevice],
+    ) -> Result<Self> {
+        if devices.len() > (NR_IRQS - FIRST_DEVICE_IRQ) as usize {
+            bail!("more virtio devices than irq lines");
+        }
+        let base = u64::from(base);
+        let transports = devices
+            .iter()
+            .enumerate()
+            .map(|(index, device)| {
+                let window = base + index as u64 * u64::from(WINDOW_SIZE);
+                let irq = FIRST_DEVICE_IRQ + index as u32;
+                MmioTransport::new(
+                    memory.clone(),
+                    window,
+                    controller.clone(),
+                    irq,
+                    lifecycle.clone(),
+                    device.clone(),
+                )
+                .map(Mutex::new)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { base, transports })
+    }
+
+    /// the base address and irq of every window
+    pub(crate) fn windows(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (0..self.transports.len()).map(|index| {
+            let index = index as u32;
+            (
+                (self.base as u32) + index * WINDOW_SIZE,
+                FIRST_DEVICE_IRQ + index,
+            )
+        })
+    }
+
+    /// the doorbell, the guest wrote the register at `offset` in the window at `base`
+    pub(crate) fn notify(&self, base: u32, offset: u32) -> Result<()> {
+        let index = u64::from(base)
+            .checked_sub(self.base)
+            .map(|offset| offset / u64::from(WINDOW_SIZE));
+        let Some(transport) = index.and_then(|index| self.transports.get(index as usize)) else {
+            bail!("no virtio device at {base:#x}");
+        };
+
+        let mut transport = transport.lock().unwrap();
+        if let Err(err) = transport.notify(u64::from(offset)) {
+            // a misbehaving driver breaks its device, not the vm
+            eprintln!("virtio device at {base:#x}: {err:#}");
+            let status = transport.read(STATUS)?;
+            transport.write(STATUS, status | STATUS_NEEDS_RESET)?;
+            transport.interrupt().raise(INTERRUPT_CONFIG_CHANGE)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+pub mod balloon;
+pub mod block;
+mod mmio;
+pub mod net;
+pub mod overlay;
+pub mod p9;
+mod queue;
+pub mod rng;
+pub mod vsock;
+
+pub use mmio::Interrupt;
+pub(crate) use mmio::{SharedDevice, VirtioMmio, WINDOW_SIZE};
+pub use queue::{Descriptor, DescriptorChain, Queue};
+
+use crate::memory::GuestMemory;
+use anyhow::Result;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+pub const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+
+/// a device model behind a virtio transport
+///
+/// the transport handles feature negotiation and queue setup, the device
+/// takes over the queues once the driver is ready.
+pub trait VirtioDevice: Send {
+    /// the virtio device id, e.g. 2 for a block device
+    fn device_type(&self) -> u32;
+
+    /// the maximum size of every queue
+    fn queue_max_sizes(&self) -> Vec<u16>;
+
+    /// device specific feature bits, the transport adds its own
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// the device configuration space
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// the driver wrote `data` to the configuration space at `offset`
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// the driver is ready, `queues` holds every queue in order
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        features: u64,
+    ) -> Result<()>;
+
+    /// the driver made buffers available on `queue`
+    fn queue_notify(&mut self, queue: usize) -> Result<()>;
+
+    /// the driver reset the device, or the vm rebooted
+    fn reset(&mut self) {}
+}
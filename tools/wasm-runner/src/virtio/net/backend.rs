@@ -0,0 +1,208 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    os::unix::{fs::FileTypeExt, net::UnixDatagram},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// frames a hub port buffers before it drops them
+const HUB_PORT_FRAMES: usize = 256;
+
+/// the other end of a network link
+pub trait NetBackend: Send + Sync {
+    /// put `frame` on the link, frames that can not be delivered are dropped
+    fn send(&self, frame: &[u8]) -> Result<()>;
+
+    /// block until a frame arrives, returns its length, or none once
+    /// `timeout` passed without one
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>>;
+}
+
+/// a link to another process through a pair of unix datagram sockets
+pub struct UnixBackend {
+    socket: UnixDatagram,
+    peer: PathBuf,
+}
+
+impl UnixBackend {
+    /// bind to `path` and send frames to the socket at `peer`
+    pub fn bind(path: &Path, peer: &Path) -> Result<Self> {
+        // a socket left behind by an earlier run
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let socket = UnixDatagram::bind(path)
+            .with_context(|| format!("while binding the network to {}", path.display()))?;
+        Ok(Self {
+            socket,
+            peer: peer.to_path_buf(),
+        })
+    }
+}
+
+impl NetBackend for UnixBackend {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        match self.socket.send_to(frame, &self.peer) {
+            Ok(_) => Ok(()),
+            // the peer is not running (yet), the link is down
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::NotFound | ErrorKind::ConnectionRefused | ErrorKind::WouldBlock
+                ) =>
+            {
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        match self.socket.recv(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// copy a frame received from a channel into `buf`, truncating it
+pub(super) fn copy_frame(
+    frame: Result<Vec<u8>, RecvTimeoutError>,
+    buf: &mut [u8],
+) -> Result<Option<usize>> {
+    let frame = match frame {
+        Ok(frame) => frame,
+        Err(RecvTimeoutError::Timeout) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let len = frame.len().min(buf.len());
+    buf[..len].copy_from_slice(&frame[..len]);
+    Ok(Some(len))
+}
+
+struct Hub {
+    ports: Mutex<Vec<(usize, SyncSender<Vec<u8>>)>>,
+}
+
+static HUBS: Mutex<BTreeMap<String, Arc<Hub>>> = Mutex::new(BTreeMap::new());
+
+/// a port on an in-process hub, every frame goes to all other ports
+///
+/// devices that join a hub with the same name are on one segment, whether
+/// they belong to the same vm or to different vms in this process.
+pub struct HubPort {
+    hub: Arc<Hub>,
+    id: usize,
+    frames: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl HubPort {
+    pub fn connect(name: &str) -> Self {
+        let hub = HUBS
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(Hub {
+                    ports: Mutex::new(Vec::new()),
+                })
+            })
+            .clone();
+
+        let (sender, frames) = mpsc::sync_channel(HUB_PORT_FRAMES);
+        let mut ports = hub.ports.lock().unwrap();
+        let id = ports.last().map_or(0, |(id, _)| id + 1);
+        ports.push((id, sender));
+        drop(ports);
+
+        Self {
+            hub,
+            id,
+            frames: Mutex::new(frames),
+        }
+    }
+}
+
+impl NetBackend for HubPort {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        for (id, port) in self.hub.ports.lock().unwrap().iter() {
+            if *id != self.id {
+                // a full port loses the frame, like a congested switch
+                let _ = port.try_send(frame.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        copy_frame(self.frames.lock().unwrap().recv_timeout(timeout), buf)
+    }
+}
+
+impl Drop for HubPort {
+    fn drop(&mut self) {
+        self.hub
+            .ports
+            .lock()
+            .unwrap()
+            .retain(|(id, _)| *id != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    fn recv(port: &HubPort) -> Option<Vec<u8>> {
+        let mut buf = [0; 64];
+        let len = port.recv(&mut buf, TIMEOUT).unwrap()?;
+        Some(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn hubs_forward_to_every_other_port() {
+        let a = HubPort::connect("test-forward");
+        let b = HubPort::connect("test-forward");
+        let c = HubPort::connect("test-forward");
+        let elsewhere = HubPort::connect("test-forward-elsewhere");
+
+        a.send(b"frame").unwrap();
+        assert_eq!(recv(&b).as_deref(), Some(&b"frame"[..]));
+        assert_eq!(recv(&c).as_deref(), Some(&b"frame"[..]));
+        // not back to the sender, nor to another hub
+        assert_eq!(recv(&a), None);
+        assert_eq!(recv(&elsewhere), None);
+
+        // a port that left gets nothing more, the others still do
+        drop(c);
+        b.send(b"again").unwrap();
+        assert_eq!(recv(&a).as_deref(), Some(&b"again"[..]));
+        assert_eq!(a.hub.ports.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn full_hub_ports_drop_frames() {
+        let a = HubPort::connect("test-full");
+        let b = HubPort::connect("test-full");
+        for index in 0..HUB_PORT_FRAMES + 10 {
+            a.send(&(index as u32).to_le_bytes()).unwrap();
+        }
+        for index in 0..HUB_PORT_FRAMES {
+            assert_eq!(recv(&b), Some((index as u32).to_le_bytes().to_vec()));
+        }
+        assert_eq!(recv(&b), None);
+    }
+}
@@ -0,0 +1,406 @@
+mod backend;
+mod pcap;
+mod user;
+
+pub use backend::{HubPort, NetBackend, UnixBackend};
+pub use pcap::Pcap;
+pub use user::{HostFwd, UserNet};
+
+use super::{Interrupt, Queue, VirtioDevice};
+use crate::{lifecycle::Lifecycle, memory::GuestMemory};
+use anyhow::{bail, Context, Result};
+use rand::Rng;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+const VIRTIO_ID_NET: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const QUEUE_SIZE: u16 = 256;
+
+/// virtio_net_hdr with num_buffers, which version 1 devices always use
+const NET_HDR_LEN: usize = 12;
+/// an ethernet frame with a vlan tag
+const MAX_FRAME_LEN: usize = 1522;
+/// frames that wait for receive buffers before new ones are dropped
+const PENDING_FRAMES: usize = 256;
+/// how long the receive thread waits for a frame before it checks whether
+/// it should stop
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// the backend part of a `--net` option
+#[derive(Clone, Debug)]
+pub enum NetBackendOptions {
+    /// `unix=<path>,peer=<path>`
+    Unix { path: PathBuf, peer: PathBuf },
+    /// `hub=<name>`
+    Hub(String),
+    /// `user`, with the runner as router
+    User { hostfwd: Vec<HostFwd> },
+}
+
+/// the value of a `--net` option,
+/// `unix=<path>,peer=<path>|hub=<name>|user[,mac=<mac>][,pcap=<file>]`
+#[derive(Clone, Debug)]
+pub struct NetOptions {
+    pub backend: NetBackendOptions,
+    /// a random locally administered address if not set
+    pub mac: Option<[u8; 6]>,
+    /// write every frame to this file
+    pub pcap: Option<PathBuf>,
+}
+
+fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    let mut bytes = [0; 6];
+    let mut parts = mac.split(':');
+    for byte in bytes.iter_mut() {
+        let part = parts.next().context("a mac address has six bytes")?;
+        *byte =
+            u8::from_str_radix(part, 16).with_context(|| format!("invalid mac address {mac}"))?;
+    }
+    if parts.next().is_some() {
+        bail!("a mac address has six bytes");
+    }
+    Ok(bytes)
+}
+
+impl FromStr for NetOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(options: &str) -> Result<Self> {
+        let mut unix = None;
+        let mut peer = None;
+        let mut hub = None;
+        let mut user = false;
+        let mut mac = None;
+        let mut pcap = None;
+        for option in options.split(',') {
+            if option == "user" {
+                user = true;
+                continue;
+            }
+            match option.split_once('=') {
+                Some(("unix", path)) => unix = Some(PathBuf::from(path)),
+                Some(("peer", path)) => peer = Some(PathBuf::from(path)),
+                Some(("hub", name)) => hub = Some(name.to_string()),
+                Some(("mac", value)) => mac = Some(parse_mac(value)?),
+                Some(("pcap", path)) => pcap = Some(PathBuf::from(path)),
+                _ => bail!("unknown net option {option}"),
+            }
+        }
+        let backend = match (unix, peer, hub, user) {
+            (Some(path), Some(peer), None, false) => NetBackendOptions::Unix { path, peer },
+            (Some(_), None, None, false) => bail!("a unix network needs a peer"),
+            (None, None, Some(name), false) => NetBackendOptions::Hub(name),
+            (None, None, None, true) => NetBackendOptions::User {
+                hostfwd: Vec::new(),
+            },
+            _ => bail!("a network needs one of unix=<path>,peer=<path>, hub=<name> or user"),
+        };
+        Ok(Self { backend, mac, pcap })
+    }
+}
+
+struct Active {
+    memory: GuestMemory,
+    interrupt: Interrupt,
+    queue: Queue,
+}
+
+/// the receive side, shared with the thread reading from the backend
+#[derive(Default)]
+struct Rx {
+    active: Option<Active>,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Rx {
+    fn push(&mut self, frame: Vec<u8>) -> Result<()> {
+        // frames for a device the driver has not set up go nowhere
+        if self.active.is_none() || self.pending.len() >= PENDING_FRAMES {
+            return Ok(());
+        }
+        self.pending.push_back(frame);
+        self.deliver()
+    }
+
+    /// move pending frames into the buffers the driver made available
+    fn deliver(&mut self) -> Result<()> {
+        let Self { active, pending } = self;
+        let Some(active) = active else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while !pending.is_empty() {
+            let Some(buffer) = active.queue.pop(&active.memory)? else {
+                break;
+            };
+            let frame = pending.pop_front().unwrap_or_default();
+
+            let mut packet = vec![0; NET_HDR_LEN];
+            // num_buffers, always one without mergeable receive buffers
+            packet[10..12].copy_from_slice(&1u16.to_le_bytes());
+            packet.extend_from_slice(&frame);
+            // a buffer too small for the frame gets nothing, the frame is lost
+            let len = if buffer.writable_len() >= packet.len() as u64 {
+                buffer.write_all(&active.memory, &packet)?
+            } else {
+                0
+            };
+            active.queue.add_used(&active.memory, buffer.head, len)?;
+            used = true;
+        }
+
+        if used {
+            active.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+}
+
+/// virtio-net with one receive and one transmit queue
+pub struct Net {
+    backend: Arc<dyn NetBackend>,
+    capture: Option<Arc<Pcap>>,
+    mac: [u8; 6],
+    rx: Arc<Mutex<Rx>>,
+    /// cleared to stop the receive thread of the current activation
+    receiving: Option<Arc<AtomicBool>>,
+    tx: Option<Active>,
+}
+
+impl Net {
+    /// a device on `backend`, frames are also written to `capture`
+    pub fn new(
+        backend: impl NetBackend + 'static,
+        mac: [u8; 6],
+        capture: Option<Pcap>,
+    ) -> Result<Self> {
+        Ok(Self {
+            backend: Arc::new(backend),
+            capture: capture.map(Arc::new),
+            mac,
+            rx: Arc::default(),
+            receiving: None,
+            tx: None,
+        })
+    }
+
+    /// pass frames from the backend to the driver until the device is reset
+    /// or the vm stops
+    fn start_rx(&mut self, lifecycle: &Arc<Lifecycle>) -> Result<()> {
+        let receiving = Arc::new(AtomicBool::new(true));
+        if let Some(previous) = self.receiving.replace(receiving.clone()) {
+            previous.store(false, Ordering::SeqCst);
+        }
+
+        let backend = self.backend.clone();
+        let capture = self.capture.clone();
+        let rx = self.rx.clone();
+        let stopped = lifecycle.clone();
+        let handle = std::thread::Builder::new()
+            .name(String::from("virtio-net"))
+            .spawn(move || {
+                let mut buf = vec![0; MAX_FRAME_LEN];
+                while receiving.load(Ordering::SeqCst) && !stopped.is_stopped() {
+                    let len = match backend.recv(&mut buf, RECV_TIMEOUT) {
+                        Ok(Some(len)) => len,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            eprintln!("virtio-net: while receiving: {err:#}");
+                            return;
+                        }
+                    };
+                    let frame = buf[..len].to_vec();
+                    if let Some(capture) = &capture {
+                        if let Err(err) = capture.write(&frame) {
+                            eprintln!("virtio-net: while capturing: {err:#}");
+                        }
+                    }
+                    if let Err(err) = rx.lock().unwrap().push(frame) {
+                        eprintln!("virtio-net: {err:#}");
+                    }
+                }
+            })?;
+        lifecycle.track(handle);
+        Ok(())
+    }
+
+    /// a device for the link in `options`
+    pub fn open(options: &NetOptions) -> Result<Self> {
+        let mac = options.mac.unwrap_or_else(|| {
+            let mut mac = [0x52, 0x54, 0x00, 0, 0, 0];
+            rand::thread_rng().fill(&mut mac[3..]);
+            mac
+        });
+        let capture = options.pcap.as_deref().map(Pcap::create).transpose()?;
+        match &options.backend {
+            NetBackendOptions::Unix { path, peer } => {
+                Self::new(UnixBackend::bind(path, peer)?, mac, capture)
+            }
+            NetBackendOptions::Hub(name) => Self::new(HubPort::connect(name), mac, capture),
+            NetBackendOptions::User { hostfwd } => Self::new(UserNet::new(hostfwd)?, mac, capture),
+        }
+    }
+
+    fn transmit(&mut self) -> Result<()> {
+        let Self {
+            backend,
+            capture,
+            tx,
+            ..
+        } = self;
+        let Some(tx) = tx else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while let Some(request) = tx.queue.pop(&tx.memory)? {
+            let packet = request.read_all(&tx.memory)?;
+            tx.queue.add_used(&tx.memory, request.head, 0)?;
+            used = true;
+
+            let Some(frame) = packet.get(NET_HDR_LEN..) else {
+                bail!("transmitted packet without a header");
+            };
+            // the host side of the link failing does not break the device
+            if let Err(err) = send(backend.as_ref(), capture.as_deref(), frame) {
+                eprintln!("virtio-net: while sending: {err:#}");
+            }
+        }
+
+        if used {
+            tx.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+}
+
+fn send(backend: &dyn NetBackend, capture: Option<&Pcap>, frame: &[u8]) -> Result<()> {
+    if let Some(capture) = capture {
+        capture.write(frame)?;
+    }
+    backend.send(frame)
+}
+
+impl VirtioDevice for Net {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        _features: u64,
+    ) -> Result<()> {
+        let Ok([rx, tx]) = <[Queue; 2]>::try_from(queues) else {
+            bail!("virtio-net needs a receive and a transmit queue");
+        };
+        self.tx = Some(Active {
+            memory: memory.clone(),
+            interrupt: interrupt.clone(),
+            queue: tx,
+        });
+        let lifecycle = interrupt.lifecycle().clone();
+        self.rx.lock().unwrap().active = Some(Active {
+            memory,
+            interrupt,
+            queue: rx,
+        });
+        self.start_rx(&lifecycle)
+    }
+
+    fn queue_notify(&mut self, queue: usize) -> Result<()> {
+        match queue {
+            RX_QUEUE => self.rx.lock().unwrap().deliver(),
+            TX_QUEUE => self.transmit(),
+            _ => bail!("virtio-net has no queue {queue}"),
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(receiving) = self.receiving.take() {
+            receiving.store(false, Ordering::SeqCst);
+        }
+        self.tx = None;
+        *self.rx.lock().unwrap() = Rx::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_options() {
+        let options = "unix=/tmp/a.sock,peer=/tmp/b.sock,mac=52:54:00:ab:cd:ef,pcap=net.pcap"
+            .parse::<NetOptions>()
+            .unwrap();
+        let NetBackendOptions::Unix { path, peer } = &options.backend else {
+            panic!("expected a unix backend, got {:?}", options.backend);
+        };
+        assert_eq!(path, &PathBuf::from("/tmp/a.sock"));
+        assert_eq!(peer, &PathBuf::from("/tmp/b.sock"));
+        assert_eq!(options.mac, Some([0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]));
+        assert_eq!(options.pcap, Some(PathBuf::from("net.pcap")));
+
+        let options = "hub=lan".parse::<NetOptions>().unwrap();
+        assert!(matches!(&options.backend, NetBackendOptions::Hub(name) if name == "lan"));
+        assert_eq!(options.mac, None);
+        assert_eq!(options.pcap, None);
+
+        let options = "user,mac=02:00:00:00:00:01".parse::<NetOptions>().unwrap();
+        assert!(
+            matches!(&options.backend, NetBackendOptions::User { hostfwd } if hostfwd.is_empty())
+        );
+        assert_eq!(options.mac, Some([2, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn invalid_net_options() {
+        for options in [
+            "",
+            "unix=/tmp/a.sock",
+            "peer=/tmp/b.sock",
+            "hub=lan,user",
+            "hub=lan,unix=/tmp/a.sock,peer=/tmp/b.sock",
+            "user,mac=52:54:00:ab:cd",
+            "user,mac=52:54:00:ab:cd:ef:01",
+            "user,mac=52:54:00:ab:cd:xy",
+            "user,vlan=1",
+        ] {
+            assert!(options.parse::<NetOptions>().is_err(), "{options}");
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const LINKTYPE_ETHERNET: u32 = 1;
+const SNAPLEN: u32 = 0xffff;
+
+/// a capture of every frame in pcap format, readable by wireshark and tcpdump
+pub struct Pcap {
+    file: Mutex<File>,
+}
+
+impl Pcap {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file =
+            File::create(path).with_context(|| format!("while creating {}", path.display()))?;
+
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // thiszone and sigfigs
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, frame: &[u8]) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let captured = frame.len().min(SNAPLEN as usize);
+
+        let mut record = Vec::with_capacity(16 + captured);
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame[..captured]);
+        // one write per record, so the capture can be followed while it grows
+        self.file.lock().unwrap().write_all(&record)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_and_records() {
+        let dir = TempDir::new("pcap");
+        let path = dir.join("net.pcap");
+        let pcap = Pcap::create(&path).unwrap();
+        pcap.write(b"first").unwrap();
+        pcap.write(&[0xab; 60]).unwrap();
+
+        let capture = std::fs::read(&path).unwrap();
+        assert_eq!(capture.len(), 24 + 16 + 5 + 16 + 60);
+        assert_eq!(u32_at(&capture, 0), PCAP_MAGIC);
+        // version 2.4
+        assert_eq!(capture[4..8], [2, 0, 4, 0]);
+        assert_eq!(capture[8..16], [0; 8]);
+        assert_eq!(u32_at(&capture, 16), SNAPLEN);
+        assert_eq!(u32_at(&capture, 20), LINKTYPE_ETHERNET);
+
+        let record = &capture[24..];
+        assert!(u32_at(record, 0) > 0);
+        assert!(u32_at(record, 4) < 1_000_000);
+        assert_eq!(u32_at(record, 8), 5);
+        assert_eq!(u32_at(record, 12), 5);
+        assert_eq!(&record[16..21], b"first");
+
+        let record = &record[21..];
+        assert_eq!(u32_at(record, 8), 60);
+        assert_eq!(u32_at(record, 12), 60);
+        assert_eq!(record[16..], [0xab; 60]);
+    }
+}
@@ -0,0 +1,182 @@
+//! a dhcp server that hands out the one guest address
+
+use super::{DNS, GATEWAY, GUEST, NETMASK};
+use std::net::Ipv4Addr;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// op to file, the fixed part of a bootp message
+const BOOTP_LEN: usize = 236;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+
+const LEASE_SECONDS: u32 = 24 * 60 * 60;
+
+fn message_type(options: &[u8]) -> Option<u8> {
+    let mut options = options;
+    loop {
+        match *options {
+            [OPTION_PAD, ref rest @ ..] => options = rest,
+            [OPTION_MESSAGE_TYPE, 1, kind, ..] => return Some(kind),
+            [OPTION_END, ..] | [] => return None,
+            [_, len, ref rest @ ..] => options = rest.get(usize::from(len)..)?,
+            _ => return None,
+        }
+    }
+}
+
+/// the reply to a dhcp request, and the client's hardware address
+pub(super) fn reply(request: &[u8]) -> Option<(Vec<u8>, [u8; 6])> {
+    let bootp = request.get(..BOOTP_LEN)?;
+    if bootp[0] != BOOTREQUEST || request.get(BOOTP_LEN..BOOTP_LEN + 4)? != MAGIC_COOKIE {
+        return None;
+    }
+    let reply_type = match message_type(&request[BOOTP_LEN + 4..])? {
+        DHCPDISCOVER => DHCPOFFER,
+        DHCPREQUEST => DHCPACK,
+        _ => return None,
+    };
+
+    let mut reply = vec![0; BOOTP_LEN];
+    reply[0] = BOOTREPLY;
+    // htype, hlen and hops
+    reply[1..4].copy_from_slice(&bootp[1..4]);
+    // xid, secs and flags
+    reply[4..12].copy_from_slice(&bootp[4..12]);
+    reply[16..20].copy_from_slice(&GUEST.octets());
+    reply[20..24].copy_from_slice(&GATEWAY.octets());
+    // chaddr
+    reply[28..44].copy_from_slice(&bootp[28..44]);
+    reply.extend_from_slice(&MAGIC_COOKIE);
+
+    let mut option = |code: u8, data: &[u8]| {
+        reply.push(code);
+        reply.push(data.len() as u8);
+        reply.extend_from_slice(data);
+    };
+    option(OPTION_MESSAGE_TYPE, &[reply_type]);
+    option(OPTION_SERVER_ID, &GATEWAY.octets());
+    option(OPTION_LEASE_TIME, &LEASE_SECONDS.to_be_bytes());
+    option(OPTION_SUBNET_MASK, &NETMASK.octets());
+    option(OPTION_ROUTER, &GATEWAY.octets());
+    option(OPTION_DNS, &DNS.octets());
+    reply.push(OPTION_END);
+
+    let mac = bootp[28..34].try_into().ok()?;
+    Some((reply, mac))
+}
+
+/// where replies go, the guest has no address before the ack
+pub(super) const REPLY_ADDR: Ipv4Addr = Ipv4Addr::BROADCAST;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const XID: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+    fn request(options: &[u8]) -> Vec<u8> {
+        let mut request = vec![0; BOOTP_LEN];
+        request[0] = BOOTREQUEST;
+        // ethernet, six byte addresses
+        request[1] = 1;
+        request[2] = 6;
+        request[4..8].copy_from_slice(&XID);
+        request[28..34].copy_from_slice(&MAC);
+        request.extend_from_slice(&MAGIC_COOKIE);
+        request.extend_from_slice(options);
+        request
+    }
+
+    /// the data of option `code` in a reply
+    fn option(reply: &[u8], code: u8) -> Option<&[u8]> {
+        let mut options = &reply[BOOTP_LEN + 4..];
+        while let [kind, len, rest @ ..] = options {
+            let (data, rest) = rest.split_at(usize::from(*len));
+            if *kind == code {
+                return Some(data);
+            }
+            options = rest;
+        }
+        None
+    }
+
+    #[test]
+    fn offers_and_acks_the_guest_address() {
+        for (kind, reply_type) in [(DHCPDISCOVER, DHCPOFFER), (DHCPREQUEST, DHCPACK)] {
+            // padding before the message type is skipped
+            let (reply, mac) = reply(&request(&[
+                OPTION_PAD,
+                OPTION_LEASE_TIME,
+                4,
+                0,
+                0,
+                0,
+                1,
+                OPTION_MESSAGE_TYPE,
+                1,
+                kind,
+                OPTION_END,
+            ]))
+            .unwrap();
+            assert_eq!(mac, MAC);
+            assert_eq!(reply[0], BOOTREPLY);
+            assert_eq!(reply[1..3], [1, 6]);
+            assert_eq!(reply[4..8], XID);
+            assert_eq!(reply[16..20], GUEST.octets());
+            assert_eq!(reply[20..24], GATEWAY.octets());
+            assert_eq!(reply[28..34], MAC);
+            assert_eq!(reply[BOOTP_LEN..BOOTP_LEN + 4], MAGIC_COOKIE);
+            assert_eq!(reply.last(), Some(&OPTION_END));
+
+            assert_eq!(option(&reply, OPTION_MESSAGE_TYPE), Some(&[reply_type][..]));
+            assert_eq!(
+                option(&reply, OPTION_SERVER_ID),
+                Some(&GATEWAY.octets()[..])
+            );
+            assert_eq!(
+                option(&reply, OPTION_SUBNET_MASK),
+                Some(&NETMASK.octets()[..])
+            );
+            assert_eq!(option(&reply, OPTION_ROUTER), Some(&GATEWAY.octets()[..]));
+            assert_eq!(option(&reply, OPTION_DNS), Some(&DNS.octets()[..]));
+            assert_eq!(
+                option(&reply, OPTION_LEASE_TIME),
+                Some(&LEASE_SECONDS.to_be_bytes()[..])
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        // a release
+        assert!(reply(&request(&[OPTION_MESSAGE_TYPE, 1, 7, OPTION_END])).is_none());
+        // no message type, plain bootp
+        assert!(reply(&request(&[OPTION_END])).is_none());
+        // an option running past the end
+        assert!(reply(&request(&[OPTION_LEASE_TIME, 4, 0])).is_none());
+
+        let discover = request(&[OPTION_MESSAGE_TYPE, 1, DHCPDISCOVER, OPTION_END]);
+        let mut wrong_op = discover.clone();
+        wrong_op[0] = BOOTREPLY;
+        assert!(reply(&wrong_op).is_none());
+        let mut wrong_cookie = discover.clone();
+        wrong_cookie[BOOTP_LEN] = 0;
+        assert!(reply(&wrong_cookie).is_none());
+        assert!(reply(&discover[..BOOTP_LEN + 2]).is_none());
+    }
+}
@@ -0,0 +1,528 @@
+//! user-mode networking, the runner plays router for the guest
+//!
+//! the guest sits in 10.0.2.0/24 like with qemu's slirp. 10.0.2.2 is the
+//! gateway and reaches the host's loopback, 10.0.2.3 forwards dns to the
+//! host's resolver and dhcp hands out 10.0.2.15. tcp and udp to anywhere else
+//! go out through ordinary host sockets, so no root or tap devices are needed.
+
+mod dhcp;
+mod packet;
+mod tcp;
+
+use super::{backend::copy_frame, NetBackend};
+use anyhow::{bail, Context, Result};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+};
+use packet::{
+    ethernet, ipv4, parse_ethernet, parse_ipv4, parse_tcp, parse_udp, udp, ETHERTYPE_ARP,
+    ETHERTYPE_IPV4, MAX_UDP_PAYLOAD, PROTO_ICMP, PROTO_TCP, PROTO_UDP,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket},
+    os::{fd::AsFd, unix::net::UnixDatagram},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tcp::{TcpConnection, TcpKey};
+
+const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+const DNS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DNS_PORT: u16 = 53;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// how long a udp flow lives without traffic from the guest
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+/// how often the udp thread looks for idle flows, in milliseconds
+const UDP_POLL_TIMEOUT: u16 = 1000;
+/// source ports of forwarded connections, as the guest sees them
+const FIRST_FORWARD_PORT: u16 = 49152;
+
+/// a host port forwarded into the guest,
+/// `tcp:[<host addr>]:<host port>-[<guest addr>]:<guest port>`
+#[derive(Clone, Debug)]
+pub struct HostFwd {
+    pub host: SocketAddr,
+    pub guest: SocketAddrV4,
+}
+
+impl FromStr for HostFwd {
+    type Err = anyhow::Error;
+
+    fn from_str(forward: &str) -> Result<Self> {
+        let Some((protocol, addrs)) = forward.split_once(':') else {
+            bail!("expected tcp:[<host addr>]:<host port>-[<guest addr>]:<guest port>");
+        };
+        if protocol != "tcp" {
+            bail!("only tcp ports can be forwarded, not {protocol}");
+        }
+        let Some((host, guest)) = addrs.split_once('-') else {
+            bail!("expected <host>-<guest> in {forward}");
+        };
+
+        let (host_addr, host_port) = host.rsplit_once(':').unwrap_or(("", host));
+        let host_addr = match host_addr {
+            "" => Ipv4Addr::LOCALHOST,
+            addr => addr.parse().context("invalid host address")?,
+        };
+        let (guest_addr, guest_port) = guest.rsplit_once(':').unwrap_or(("", guest));
+        let guest_addr = match guest_addr {
+            "" => GUEST,
+            addr => addr.parse().context("invalid guest address")?,
+        };
+
+        Ok(Self {
+            host: SocketAddr::from((host_addr, host_port.parse().context("invalid host port")?)),
+            guest: SocketAddrV4::new(
+                guest_addr,
+                guest_port.parse().context("invalid guest port")?,
+            ),
+        })
+    }
+}
+
+/// the guest's and the remote address of a udp flow
+type UdpKey = (SocketAddrV4, SocketAddrV4);
+
+struct UdpFlow {
+    socket: UdpSocket,
+    host: SocketAddrV4,
+    last_used: Mutex<Instant>,
+}
+
+/// the runner's side of the network, shared by every socket thread
+struct Stack {
+    frames: Sender<Vec<u8>>,
+    /// broadcast until the guest sends its first frame
+    guest_mac: Mutex<[u8; 6]>,
+    resolver: Option<Ipv4Addr>,
+    udp: Mutex<HashMap<UdpKey, Arc<UdpFlow>>>,
+    /// tells the udp thread about a new flow
+    udp_added: UnixDatagram,
+    tcp: Mutex<HashMap<TcpKey, Arc<TcpConnection>>>,
+    next_port: AtomicU16,
+    /// identifies the fragments of a datagram to the guest
+    next_ip_id: AtomicU16,
+}
+
+/// the first ipv4 nameserver in /etc/resolv.conf
+fn host_resolver() -> Option<Ipv4Addr> {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(addr)) => addr.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+impl Stack {
+    fn send_frame(&self, ethertype: u16, payload: &[u8]) {
+        let guest_mac = *self.guest_mac.lock().unwrap();
+        let _ = self
+            .frames
+            .send(ethernet(guest_mac, GATEWAY_MAC, ethertype, payload));
+    }
+
+    fn send_ip(&self, packet: &[u8]) {
+        self.send_frame(ETHERTYPE_IPV4, packet);
+    }
+
+    /// where a guest packet to `addr` goes on the host, if anywhere
+    fn host_addr(&self, addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        match *addr.ip() {
+            GATEWAY => Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, addr.port())),
+            DNS if addr.port() == DNS_PORT => Some(SocketAddrV4::new(self.resolver?, DNS_PORT)),
+            ip if ip.octets()[..3] == GATEWAY.octets()[..3] => None,
+            _ => Some(addr),
+        }
+    }
+
+    fn input(self: &Arc<Self>, frame: &[u8]) {
+        let Some(ethernet) = parse_ethernet(frame) else {
+            return;
+        };
+        *self.guest_mac.lock().unwrap() = ethernet.src;
+        match ethernet.ethertype {
+            ETHERTYPE_ARP => self.arp(ethernet.payload),
+            ETHERTYPE_IPV4 => self.ipv4(ethernet.payload),
+            _ => {}
+        }
+    }
+
+    /// answer for every address on the network that is not the guest's
+    fn arp(&self, packet: &[u8]) {
+        let Some(packet) = packet.get(..28) else {
+            return;
+        };
+        let operation = u16::from_be_bytes([packet[6], packet[7]]);
+        let target = Ipv4Addr::new(packet[24], packet[25], packet[26], packet[27]);
+        if operation != ARP_REQUEST || !matches!(target, GATEWAY | DNS) {
+            return;
+        }
+
+        let mut reply = packet[..8].to_vec();
+        reply[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        reply.extend_from_slice(&GATEWAY_MAC);
+        reply.extend_from_slice(&target.octets());
+        // the sender becomes the target
+        reply.extend_from_slice(&packet[8..18]);
+        self.send_frame(ETHERTYPE_ARP, &reply);
+    }
+
+    fn ipv4(self: &Arc<Self>, packet: &[u8]) {
+        let Some(packet) = parse_ipv4(packet) else {
+            return;
+        };
+        match packet.protocol {
+            PROTO_ICMP if matches!(packet.dst, GATEWAY | DNS) => {
+                self.icmp(packet.src, packet.dst, packet.payload)
+            }
+            PROTO_UDP => {
+                let Some(datagram) = parse_udp(packet.payload) else {
+                    return;
+                };
+                let src = SocketAddrV4::new(packet.src, datagram.src_port);
+                let dst = SocketAddrV4::new(packet.dst, datagram.dst_port);
+                if dst.port() == DHCP_SERVER_PORT {
+                    self.dhcp(datagram.payload);
+                } else if let Err(err) = self.udp(src, dst, datagram.payload) {
+                    eprintln!("user-net: udp to {dst}: {err:#}");
+                }
+            }
+            PROTO_TCP => {
+                let Some(segment) = parse_tcp(packet.payload) else {
+                    return;
+                };
+                let key = (
+                    SocketAddrV4::new(packet.src, segment.src_port),
+                    SocketAddrV4::new(packet.dst, segment.dst_port),
+                );
+                let connection = self.tcp.lock().unwrap().get(&key).cloned();
+                match connection {
+                    Some(connection) => connection.input(&segment),
+                    None => match self.host_addr(key.1) {
+                        Some(host) if segment.header.flags & packet::TCP_SYN != 0 => {
+                            TcpConnection::connect(self, key, host, &segment)
+                        }
+                        _ => tcp::reset(self, key, &segment),
+                    },
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// the gateway and the dns server answer pings themselves
+    fn icmp(&self, src: Ipv4Addr, dst: Ipv4Addr, message: &[u8]) {
+        if message.len() < 8 || message[0] != ICMP_ECHO_REQUEST {
+            return;
+        }
+        let mut reply = message.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].fill(0);
+        let checksum = packet::checksum(&reply, 0);
+        reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+        self.send_ip(&ipv4(dst, src, PROTO_ICMP, &reply));
+    }
+
+    fn dhcp(&self, request: &[u8]) {
+        let Some((reply, mac)) = dhcp::reply(request) else {
+            return;
+        };
+        let packets = udp(
+            SocketAddrV4::new(GATEWAY, DHCP_SERVER_PORT),
+            SocketAddrV4::new(dhcp::REPLY_ADDR, DHCP_CLIENT_PORT),
+            self.next_ip_id.fetch_add(1, Ordering::Relaxed),
+            &reply,
+        );
+        for packet in packets {
+            let _ = self
+                .frames
+                .send(ethernet(mac, GATEWAY_MAC, ETHERTYPE_IPV4, &packet));
+        }
+    }
+
+    /// send a guest datagram from a host socket kept for the flow
+    fn udp(&self, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Result<()> {
+        let Some(host) = self.host_addr(dst) else {
+            return Ok(());
+        };
+        let key = (src, dst);
+
+        let flow = self.udp.lock().unwrap().get(&key).cloned();
+        let flow = match flow {
+            Some(flow) => flow,
+            None => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+                socket.set_nonblocking(true)?;
+                let flow = Arc::new(UdpFlow {
+                    socket,
+                    host,
+                    last_used: Mutex::new(Instant::now()),
+                });
+                self.udp.lock().unwrap().insert(key, flow.clone());
+                let _ = self.udp_added.send(&[0]);
+                flow
+            }
+        };
+
+        *flow.last_used.lock().unwrap() = Instant::now();
+        flow.socket.send_to(payload, flow.host)?;
+        Ok(())
+    }
+
+    /// pass replies to every udp flow back to the guest and close idle
+    /// flows, until the network is dropped
+    fn udp_replies(stack: Weak<Self>, added: UnixDatagram) {
+        let mut buf = vec![0; MAX_UDP_PAYLOAD];
+        while let Some(stack) = stack.upgrade() {
+            let flows = {
+                let mut flows = stack.udp.lock().unwrap();
+                flows.retain(|_, flow| flow.last_used.lock().unwrap().elapsed() < UDP_TIMEOUT);
+                flows
+                    .iter()
+                    .map(|(&key, flow)| (key, flow.clone()))
+                    .collect::<Vec<_>>()
+            };
+
+            let mut fds = std::iter::once(added.as_fd())
+                .chain(flows.iter().map(|(_, flow)| flow.socket.as_fd()))
+                .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
+                .collect::<Vec<_>>();
+            match poll(&mut fds, PollTimeout::from(UDP_POLL_TIMEOUT)) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(err) => {
+                    eprintln!("user-net: while polling udp flows: {err}");
+                    return;
+                }
+            }
+            let readable = fds
+                .iter()
+                .map(|fd| fd.revents().is_some_and(|events| !events.is_empty()))
+                .collect::<Vec<_>>();
+
+            // new flows are picked up on the next round
+            if readable[0] {
+                while added.recv(&mut buf).is_ok() {}
+            }
+            for ((guest, remote), flow) in flows
+                .iter()
+                .zip(&readable[1..])
+                .filter_map(|(flow, &readable)| readable.then_some(flow))
+            {
+                // until drained, or an error like the icmp port unreachable an
+                // earlier datagram caused
+                while let Ok((len, _)) = flow.socket.recv_from(&mut buf) {
+                    let id = stack.next_ip_id.fetch_add(1, Ordering::Relaxed);
+                    for packet in udp(*remote, *guest, id, &buf[..len]) {
+                        stack.send_ip(&packet);
+                    }
+                }
+            }
+        }
+    }
+
+    /// a gateway port for a connection forwarded to `guest` that no other
+    /// connection uses, `connections` is the locked `tcp`
+    fn forward_key(
+        &self,
+        connections: &HashMap<TcpKey, Arc<TcpConnection>>,
+        guest: SocketAddrV4,
+    ) -> Option<TcpKey> {
+        let mut port = self.next_port.load(Ordering::Relaxed);
+        for _ in FIRST_FORWARD_PORT..=u16::MAX {
+            let key = (guest, SocketAddrV4::new(GATEWAY, port));
+            port = port.checked_add(1).unwrap_or(FIRST_FORWARD_PORT);
+            if !connections.contains_key(&key) {
+                self.next_port.store(port, Ordering::Relaxed);
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    /// accept host connections on `forward.host` and open them to the guest
+    fn listen(self: &Arc<Self>, forward: &HostFwd) -> Result<()> {
+        let listener = TcpListener::bind(forward.host)
+            .with_context(|| format!("while forwarding {}", forward.host))?;
+        let guest = forward.guest;
+        let stack = self.clone();
+        std::thread::Builder::new()
+            .name(String::from("user-net-hostfwd"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            eprintln!("user-net: while accepting on a forwarded port: {err}");
+                            continue;
+                        }
+                    };
+                    TcpConnection::forward(&stack, stream, guest);
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// a network with the runner as gateway, dhcp and dns server
+pub struct UserNet {
+    stack: Arc<Stack>,
+    frames: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl UserNet {
+    pub fn new(hostfwd: &[HostFwd]) -> Result<Self> {
+        let (sender, frames) = mpsc::channel();
+        let (udp_added, added) = UnixDatagram::pair()?;
+        udp_added.set_nonblocking(true)?;
+        added.set_nonblocking(true)?;
+        let stack = Arc::new(Stack {
+            frames: sender,
+            guest_mac: Mutex::new([0xff; 6]),
+            resolver: host_resolver(),
+            udp: Mutex::new(HashMap::new()),
+            udp_added,
+            tcp: Mutex::new(HashMap::new()),
+            next_port: AtomicU16::new(FIRST_FORWARD_PORT),
+            next_ip_id: AtomicU16::new(0),
+        });
+        for forward in hostfwd {
+            stack.listen(forward)?;
+        }
+        let replies = Arc::downgrade(&stack);
+        std::thread::Builder::new()
+            .name(String::from("user-net-udp"))
+            .spawn(move || Stack::udp_replies(replies, added))?;
+        Ok(Self {
+            stack,
+            frames: Mutex::new(frames),
+        })
+    }
+}
+
+impl NetBackend for UserNet {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        self.stack.input(frame);
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        copy_frame(self.frames.lock().unwrap().recv_timeout(timeout), buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn hostfwd(forward: &str) -> Result<(SocketAddr, SocketAddrV4)> {
+        let HostFwd { host, guest } = forward.parse()?;
+        Ok((host, guest))
+    }
+
+    #[test]
+    fn host_forwards() {
+        assert_eq!(
+            hostfwd("tcp:127.0.0.1:8022-:22").unwrap(),
+            (
+                "127.0.0.1:8022".parse().unwrap(),
+                SocketAddrV4::new(GUEST, 22)
+            )
+        );
+        assert_eq!(
+            hostfwd("tcp:0.0.0.0:8080-10.0.2.16:80").unwrap(),
+            (
+                "0.0.0.0:8080".parse().unwrap(),
+                "10.0.2.16:80".parse().unwrap()
+            )
+        );
+        // the host's loopback and the guest's address by default
+        for forward in ["tcp::8022-:22", "tcp:8022-22"] {
+            assert_eq!(
+                hostfwd(forward).unwrap(),
+                (
+                    "127.0.0.1:8022".parse().unwrap(),
+                    SocketAddrV4::new(GUEST, 22)
+                ),
+                "{forward}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_host_forwards() {
+        for forward in [
+            "",
+            "tcp",
+            "udp:127.0.0.1:5353-:53",
+            "tcp:8022",
+            "tcp:8022-",
+            "tcp:localhost:8022-:22",
+            "tcp::8022-guest:22",
+            "tcp::70000-:22",
+            "tcp::8022-:ssh",
+        ] {
+            assert!(hostfwd(forward).is_err(), "{forward}");
+        }
+    }
+
+    #[test]
+    fn udp_replies_reach_the_guest() {
+        let net = UserNet::new(&[]).unwrap();
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+
+        // the gateway is the host's loopback
+        let guest = SocketAddrV4::new(GUEST, 5000);
+        let remote = SocketAddrV4::new(GATEWAY, port);
+        for packet in udp(guest, remote, 0, b"ping") {
+            net.send(&ethernet(GATEWAY_MAC, GUEST_MAC, ETHERTYPE_IPV4, &packet))
+                .unwrap();
+        }
+        let mut buf = [0; 64];
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        server.send_to(b"pong", from).unwrap();
+
+        let mut frame = vec![0; MAX_UDP_PAYLOAD];
+        let len = net
+            .recv(&mut frame, Duration::from_secs(5))
+            .unwrap()
+            .expect("the reply did not reach the guest");
+        let ethernet = parse_ethernet(&frame[..len]).unwrap();
+        assert_eq!(ethernet.src, GATEWAY_MAC);
+        assert_eq!(ethernet.ethertype, ETHERTYPE_IPV4);
+        let packet = parse_ipv4(ethernet.payload).unwrap();
+        assert_eq!((packet.src, packet.dst), (GATEWAY, GUEST));
+        let datagram = parse_udp(packet.payload).unwrap();
+        assert_eq!((datagram.src_port, datagram.dst_port), (port, 5000));
+        assert_eq!(datagram.payload, b"pong");
+        assert_eq!(net.stack.udp.lock().unwrap().len(), 1);
+    }
+}
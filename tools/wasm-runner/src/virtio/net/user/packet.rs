@@ -0,0 +1,413 @@
+//! just enough ethernet, ipv4, udp and tcp to talk to one guest
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub(super) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(super) const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub(super) const PROTO_ICMP: u8 = 1;
+pub(super) const PROTO_TCP: u8 = 6;
+pub(super) const PROTO_UDP: u8 = 17;
+
+pub(super) const TCP_FIN: u8 = 1;
+pub(super) const TCP_SYN: u8 = 2;
+pub(super) const TCP_RST: u8 = 4;
+pub(super) const TCP_PSH: u8 = 8;
+pub(super) const TCP_ACK: u8 = 16;
+
+/// the segment size announced to the guest
+pub(super) const TCP_MSS: u16 = 1460;
+
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const TCP_HEADER_LEN: usize = 20;
+
+/// the largest ipv4 packet on the guest's link
+const MTU: usize = 1500;
+/// the largest udp payload that fits an ipv4 packet at all
+pub(super) const MAX_UDP_PAYLOAD: usize = 0xffff - IPV4_HEADER_LEN - UDP_HEADER_LEN;
+
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+
+fn sum(data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    let mut sum = chunks
+        .by_ref()
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], chunk[1]])))
+        .sum::<u32>();
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+/// the internet checksum over `data`, continuing from a partial `sum`
+pub(super) fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial + sum(data);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    sum(&src.octets()) + sum(&dst.octets()) + u32::from(protocol) + len as u32
+}
+
+pub(super) struct Ethernet<'a> {
+    pub src: [u8; 6],
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+pub(super) fn parse_ethernet(frame: &[u8]) -> Option<Ethernet<'_>> {
+    Some(Ethernet {
+        src: frame.get(6..12)?.try_into().ok()?,
+        ethertype: u16::from_be_bytes(frame.get(12..14)?.try_into().ok()?),
+        payload: frame.get(14..)?,
+    })
+}
+
+pub(super) fn ethernet(dst: [u8; 6], src: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(14 + payload.len());
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub(super) struct Ipv4<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+/// an unfragmented ipv4 packet
+pub(super) fn parse_ipv4(packet: &[u8]) -> Option<Ipv4<'_>> {
+    let header = packet.get(..IPV4_HEADER_LEN)?;
+    let header_len = usize::from(header[0] & 0xf) * 4;
+    if header[0] >> 4 != 4 || header_len < IPV4_HEADER_LEN {
+        return None;
+    }
+    // more fragments or a fragment offset
+    if u16::from_be_bytes([header[6], header[7]]) & 0x3fff != 0 {
+        return None;
+    }
+    let total_len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    Some(Ipv4 {
+        src: Ipv4Addr::from(<[u8; 4]>::try_from(&header[12..16]).ok()?),
+        dst: Ipv4Addr::from(<[u8; 4]>::try_from(&header[16..20]).ok()?),
+        protocol: header[9],
+        payload: packet.get(header_len..total_len)?,
+    })
+}
+
+pub(super) fn ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    ipv4_fragment(src, dst, protocol, 0, IPV4_DONT_FRAGMENT, payload)
+}
+
+/// an ipv4 packet with `fragment`, the flags and the offset in 8 byte units
+fn ipv4_fragment(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+    fragment: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(IPV4_HEADER_LEN + payload.len());
+    packet.push(0x45);
+    packet.push(0);
+    packet.extend_from_slice(&((IPV4_HEADER_LEN + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&fragment.to_be_bytes());
+    packet.push(64);
+    packet.push(protocol);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    let checksum = checksum(&packet, 0);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub(super) struct Udp<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+pub(super) fn parse_udp(datagram: &[u8]) -> Option<Udp<'_>> {
+    let len = usize::from(u16::from_be_bytes(datagram.get(4..6)?.try_into().ok()?));
+    Some(Udp {
+        src_port: u16::from_be_bytes(datagram.get(0..2)?.try_into().ok()?),
+        dst_port: u16::from_be_bytes(datagram.get(2..4)?.try_into().ok()?),
+        payload: datagram.get(UDP_HEADER_LEN..len)?,
+    })
+}
+
+/// ipv4 packets with a udp datagram, fragments with `id` if it does not
+/// fit the mtu
+pub(super) fn udp(src: SocketAddrV4, dst: SocketAddrV4, id: u16, payload: &[u8]) -> Vec<Vec<u8>> {
+    let len = UDP_HEADER_LEN + payload.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let checksum = match checksum(
+        &datagram,
+        pseudo_header_sum(*src.ip(), *dst.ip(), PROTO_UDP, len),
+    ) {
+        // zero means no checksum for udp
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    if IPV4_HEADER_LEN + len <= MTU {
+        return vec![ipv4(*src.ip(), *dst.ip(), PROTO_UDP, &datagram)];
+    }
+
+    // every fragment but the last carries a multiple of 8 bytes
+    let max = (MTU - IPV4_HEADER_LEN) & !7;
+    datagram
+        .chunks(max)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let offset = index * max;
+            let more = if offset + chunk.len() < len {
+                IPV4_MORE_FRAGMENTS
+            } else {
+                0
+            };
+            let fragment = more | (offset / 8) as u16;
+            ipv4_fragment(*src.ip(), *dst.ip(), PROTO_UDP, id, fragment, chunk)
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct TcpHeader {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+}
+
+pub(super) struct Tcp<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub header: TcpHeader,
+    pub payload: &'a [u8],
+}
+
+impl Tcp<'_> {
+    /// how much sequence space the segment takes
+    pub fn len(&self) -> u32 {
+        let flags = self.header.flags;
+        self.payload.len() as u32
+            + u32::from(flags & TCP_SYN != 0)
+            + u32::from(flags & TCP_FIN != 0)
+    }
+}
+
+pub(super) fn parse_tcp(segment: &[u8]) -> Option<Tcp<'_>> {
+    let header = segment.get(..TCP_HEADER_LEN)?;
+    let header_len = usize::from(header[12] >> 4) * 4;
+    Some(Tcp {
+        src_port: u16::from_be_bytes([header[0], header[1]]),
+        dst_port: u16::from_be_bytes([header[2], header[3]]),
+        header: TcpHeader {
+            seq: u32::from_be_bytes(header[4..8].try_into().ok()?),
+            ack: u32::from_be_bytes(header[8..12].try_into().ok()?),
+            flags: header[13],
+            window: u16::from_be_bytes([header[14], header[15]]),
+        },
+        payload: segment.get(header_len.max(TCP_HEADER_LEN)..)?,
+    })
+}
+
+/// an ipv4 packet with a tcp segment, syns carry the mss option
+pub(super) fn tcp(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    header: &TcpHeader,
+    payload: &[u8],
+) -> Vec<u8> {
+    let options: &[u8] = if header.flags & TCP_SYN != 0 {
+        let mss = TCP_MSS.to_be_bytes();
+        &[2, 4, mss[0], mss[1]]
+    } else {
+        &[]
+    };
+    let header_len = TCP_HEADER_LEN + options.len();
+    let len = header_len + payload.len();
+
+    let mut segment = Vec::with_capacity(len);
+    segment.extend_from_slice(&src.port().to_be_bytes());
+    segment.extend_from_slice(&dst.port().to_be_bytes());
+    segment.extend_from_slice(&header.seq.to_be_bytes());
+    segment.extend_from_slice(&header.ack.to_be_bytes());
+    segment.push((header_len as u8 / 4) << 4);
+    segment.push(header.flags);
+    segment.extend_from_slice(&header.window.to_be_bytes());
+    // checksum and urgent pointer
+    segment.extend_from_slice(&[0; 4]);
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(payload);
+    let checksum = checksum(
+        &segment,
+        pseudo_header_sum(*src.ip(), *dst.ip(), PROTO_TCP, len),
+    );
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    ipv4(*src.ip(), *dst.ip(), PROTO_TCP, &segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 40000);
+    const DST: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 53);
+
+    /// a packet or segment with a valid checksum sums up to zero
+    fn verify(data: &[u8], initial: u32) {
+        assert_eq!(checksum(data, initial), 0);
+    }
+
+    #[test]
+    fn checksums() {
+        // the example from rfc 1071
+        assert_eq!(
+            checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7], 0),
+            !0xddf2
+        );
+        // an odd byte is padded with zero
+        assert_eq!(checksum(&[0x01], 0), !0x0100);
+        assert_eq!(checksum(&[0x01, 0x00], 0), checksum(&[0x01], 0));
+        // carries wrap around
+        assert_eq!(checksum(&[0xff, 0xff, 0x00, 0x01], 0), !0x0001);
+        assert_eq!(checksum(&[], 0x1_fffe), 0);
+    }
+
+    #[test]
+    fn ethernet_round_trip() {
+        let frame = ethernet([1; 6], [2; 6], ETHERTYPE_ARP, b"payload");
+        let parsed = parse_ethernet(&frame).unwrap();
+        assert_eq!(frame[..6], [1; 6]);
+        assert_eq!(parsed.src, [2; 6]);
+        assert_eq!(parsed.ethertype, ETHERTYPE_ARP);
+        assert_eq!(parsed.payload, b"payload");
+        assert!(parse_ethernet(&frame[..13]).is_none());
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        let packets = udp(SRC, DST, 7, b"query");
+        assert_eq!(packets.len(), 1);
+        let packet = &packets[0];
+        verify(&packet[..IPV4_HEADER_LEN], 0);
+
+        let ip = parse_ipv4(packet).unwrap();
+        assert_eq!(
+            (ip.src, ip.dst, ip.protocol),
+            (*SRC.ip(), *DST.ip(), PROTO_UDP)
+        );
+        verify(
+            ip.payload,
+            pseudo_header_sum(ip.src, ip.dst, PROTO_UDP, ip.payload.len()),
+        );
+        let datagram = parse_udp(ip.payload).unwrap();
+        assert_eq!(
+            (datagram.src_port, datagram.dst_port),
+            (SRC.port(), DST.port())
+        );
+        assert_eq!(datagram.payload, b"query");
+    }
+
+    #[test]
+    fn large_udp_datagrams_are_fragmented() {
+        let payload = (0..3000).map(|at| at as u8).collect::<Vec<_>>();
+        let packets = udp(SRC, DST, 7, &payload);
+        assert_eq!(packets.len(), 3);
+
+        let mut datagram = Vec::new();
+        for (index, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= MTU);
+            verify(&packet[..IPV4_HEADER_LEN], 0);
+            assert_eq!(packet[4..6], 7u16.to_be_bytes());
+            let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+            let more = index < packets.len() - 1;
+            assert_eq!(fragment & IPV4_MORE_FRAGMENTS != 0, more);
+            assert_eq!(usize::from(fragment & 0x1fff) * 8, datagram.len());
+            // the guest reassembles fragments, the runner does not take them
+            assert!(parse_ipv4(packet).is_none());
+            datagram.extend_from_slice(&packet[IPV4_HEADER_LEN..]);
+        }
+        let parsed = parse_udp(&datagram).unwrap();
+        assert_eq!(parsed.payload, payload);
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        let header = TcpHeader {
+            seq: 1000,
+            ack: 2000,
+            flags: TCP_SYN | TCP_ACK,
+            window: 0xffff,
+        };
+        let packet = tcp(SRC, DST, &header, b"");
+        let ip = parse_ipv4(&packet).unwrap();
+        assert_eq!(ip.protocol, PROTO_TCP);
+        verify(
+            ip.payload,
+            pseudo_header_sum(ip.src, ip.dst, PROTO_TCP, ip.payload.len()),
+        );
+        // syns announce the mss
+        assert_eq!(ip.payload[20..24], [2, 4, 0x05, 0xb4]);
+
+        let segment = parse_tcp(ip.payload).unwrap();
+        assert_eq!(
+            (segment.src_port, segment.dst_port),
+            (SRC.port(), DST.port())
+        );
+        assert_eq!((segment.header.seq, segment.header.ack), (1000, 2000));
+        assert_eq!(segment.header.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(segment.header.window, 0xffff);
+        assert!(segment.payload.is_empty());
+        assert_eq!(segment.len(), 1);
+
+        let header = TcpHeader {
+            flags: TCP_FIN | TCP_ACK,
+            ..header
+        };
+        let packet = tcp(SRC, DST, &header, b"data");
+        let segment = parse_tcp(parse_ipv4(&packet).unwrap().payload).unwrap();
+        assert_eq!(segment.payload, b"data");
+        assert_eq!(segment.len(), 5);
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let packet = ipv4(*SRC.ip(), *DST.ip(), PROTO_UDP, b"12345678");
+        assert!(parse_ipv4(&packet[..IPV4_HEADER_LEN - 1]).is_none());
+        // the total length runs past the packet
+        assert!(parse_ipv4(&packet[..packet.len() - 1]).is_none());
+        let mut ipv6 = packet.clone();
+        ipv6[0] = 0x65;
+        assert!(parse_ipv4(&ipv6).is_none());
+        let mut short_header = packet.clone();
+        short_header[0] = 0x44;
+        assert!(parse_ipv4(&short_header).is_none());
+
+        // a udp length past the datagram
+        assert!(parse_udp(&[0, 1, 0, 2, 0, 9, 0, 0]).is_none());
+        assert!(parse_udp(&[0, 1, 0, 2, 0]).is_none());
+        assert!(parse_tcp(&[0; TCP_HEADER_LEN - 1]).is_none());
+    }
+}
@@ -0,0 +1,476 @@
+//! tcp connections between the guest and host sockets
+//!
+//! every guest connection ends in a host `TcpStream`. the runner acks guest
+//! data as soon as it is queued for the host and keeps host data until the
+//! guest acks it, retransmitting after a timeout. there is no congestion
+//! control, the link to the guest does not lose frames unless it is full.
+
+use super::{
+    packet::{tcp, Tcp, TcpHeader, TCP_ACK, TCP_FIN, TCP_MSS, TCP_PSH, TCP_RST, TCP_SYN},
+    Stack,
+};
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    net::{Shutdown, SocketAddrV4, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// (guest address, remote address as the guest sees it)
+pub(super) type TcpKey = (SocketAddrV4, SocketAddrV4);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_RETRANSMITS: u32 = 8;
+/// host data buffered for the guest before the runner stops reading
+const SEND_BUFFER: usize = 256 * 1024;
+/// guest data queued for the host, the window the guest sees
+const RECEIVE_WINDOW: usize = 0xffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// the guest sent a syn, the host connection is not up yet
+    Connecting,
+    /// the runner answered the guest's syn
+    SynReceived,
+    /// a forwarded host connection, the runner sent a syn to the guest
+    SynSent,
+    Established,
+    Closed,
+}
+
+/// is `a` before `b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+struct Tcb {
+    state: State,
+    /// the oldest byte the guest has not acked
+    snd_una: u32,
+    /// the next byte to send
+    snd_nxt: u32,
+    /// the most that was ever sent, retransmits rewind `snd_nxt` only
+    snd_max: u32,
+    rcv_nxt: u32,
+    window: u32,
+    /// host data from `snd_una` on
+    send_buffer: VecDeque<u8>,
+    host_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    guest_fin: bool,
+    retransmit_at: Option<Instant>,
+    retransmits: u32,
+}
+
+impl Tcb {
+    fn new(state: State, iss: u32) -> Self {
+        Self {
+            state,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            rcv_nxt: 0,
+            window: 0,
+            send_buffer: VecDeque::new(),
+            host_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            guest_fin: false,
+            retransmit_at: None,
+            retransmits: 0,
+        }
+    }
+
+    fn advance(&mut self, len: u32) {
+        self.snd_nxt = self.snd_nxt.wrapping_add(len);
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+        self.retransmit_at
+            .get_or_insert_with(|| Instant::now() + RETRANSMIT_TIMEOUT);
+    }
+}
+
+pub(super) struct TcpConnection {
+    stack: Arc<Stack>,
+    key: TcpKey,
+    tcb: Mutex<Tcb>,
+    changed: Condvar,
+    stream: Mutex<Option<TcpStream>>,
+    /// chunks for the host writer thread, `None` once the guest sent a fin
+    to_host: Mutex<Option<Sender<Option<Vec<u8>>>>>,
+    to_host_pending: Arc<AtomicUsize>,
+}
+
+impl TcpConnection {
+    fn new(stack: &Arc<Stack>, key: TcpKey, tcb: Tcb) -> Arc<Self> {
+        Arc::new(Self {
+            stack: stack.clone(),
+            key,
+            tcb: Mutex::new(tcb),
+            changed: Condvar::new(),
+            stream: Mutex::new(None),
+            to_host: Mutex::new(None),
+            to_host_pending: Arc::default(),
+        })
+    }
+
+    /// the guest sent a syn to `host`, as seen from the runner
+    pub(super) fn connect(stack: &Arc<Stack>, key: TcpKey, host: SocketAddrV4, syn: &Tcp) {
+        let mut tcb = Tcb::new(State::Connecting, rand::random());
+        tcb.rcv_nxt = syn.header.seq.wrapping_add(1);
+        tcb.window = u32::from(syn.header.window);
+        let connection = Self::new(stack, key, tcb);
+        stack.tcp.lock().unwrap().insert(key, connection.clone());
+
+        let spawned = std::thread::Builder::new()
+            .name(String::from("user-net-tcp"))
+            .spawn(
+                move || match TcpStream::connect_timeout(&host.into(), CONNECT_TIMEOUT) {
+                    Ok(stream) => {
+                        let mut tcb = connection.tcb.lock().unwrap();
+                        tcb.state = State::SynReceived;
+                        connection.send(&tcb, tcb.snd_nxt, TCP_SYN | TCP_ACK, &[]);
+                        tcb.advance(1);
+                        drop(tcb);
+                        connection.start(stream);
+                    }
+                    Err(_) => connection.reset(),
+                },
+            );
+        if spawned.is_err() {
+            stack.tcp.lock().unwrap().remove(&key);
+        }
+    }
+
+    /// a host connection to a forwarded port, open one to `guest`
+    pub(super) fn forward(stack: &Arc<Stack>, stream: TcpStream, guest: SocketAddrV4) {
+        let mut connections = stack.tcp.lock().unwrap();
+        let Some(key) = stack.forward_key(&connections, guest) else {
+            eprintln!("user-net: no free port to forward a connection to {guest}");
+            return;
+        };
+        let tcb = Tcb::new(State::SynSent, rand::random());
+        let connection = Self::new(stack, key, tcb);
+        connections.insert(key, connection.clone());
+        drop(connections);
+
+        let mut tcb = connection.tcb.lock().unwrap();
+        connection.send(&tcb, tcb.snd_nxt, TCP_SYN, &[]);
+        tcb.advance(1);
+        drop(tcb);
+        connection.start(stream);
+    }
+
+    /// start the threads that move data to and from the host
+    fn start(self: &Arc<Self>, stream: TcpStream) {
+        let (Ok(reader), Ok(mut writer)) = (stream.try_clone(), stream.try_clone()) else {
+            return self.reset();
+        };
+        *self.stream.lock().unwrap() = Some(stream);
+        let (to_host, chunks) = mpsc::channel::<Option<Vec<u8>>>();
+        *self.to_host.lock().unwrap() = Some(to_host);
+
+        let connection = self.clone();
+        let pending = self.to_host_pending.clone();
+        let threads = [
+            std::thread::Builder::new()
+                .name(String::from("user-net-tcp"))
+                .spawn(move || connection.read_host(reader)),
+            std::thread::Builder::new()
+                .name(String::from("user-net-tcp"))
+                .spawn(move || {
+                    for chunk in chunks {
+                        let Some(chunk) = chunk else {
+                            let _ = writer.shutdown(Shutdown::Write);
+                            continue;
+                        };
+                        if writer.write_all(&chunk).is_err() {
+                            break;
+                        }
+                        pending.fetch_sub(chunk.len(), Ordering::SeqCst);
+                    }
+                }),
+            {
+                let connection = self.clone();
+                std::thread::Builder::new()
+                    .name(String::from("user-net-tcp"))
+                    .spawn(move || connection.retransmit_loop())
+            },
+        ];
+        if threads.iter().any(Result::is_err) {
+            self.reset();
+        }
+    }
+
+    fn read_host(&self, mut reader: TcpStream) {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let result = reader.read(&mut buf);
+            let mut tcb = self.tcb.lock().unwrap();
+            match result {
+                Ok(0) => {
+                    tcb.host_eof = true;
+                    self.output(&mut tcb);
+                    return;
+                }
+                Ok(len) => {
+                    tcb.send_buffer.extend(&buf[..len]);
+                    self.output(&mut tcb);
+                }
+                Err(_) => {
+                    if tcb.state != State::Closed {
+                        drop(tcb);
+                        self.reset();
+                    }
+                    return;
+                }
+            }
+            while tcb.send_buffer.len() >= SEND_BUFFER && tcb.state != State::Closed {
+                tcb = self.changed.wait(tcb).unwrap();
+            }
+            if tcb.state == State::Closed {
+                return;
+            }
+        }
+    }
+
+    fn retransmit_loop(&self) {
+        let mut tcb = self.tcb.lock().unwrap();
+        while tcb.state != State::Closed {
+            tcb = self
+                .changed
+                .wait_timeout(tcb, RETRANSMIT_TIMEOUT)
+                .unwrap()
+                .0;
+            let due = matches!(tcb.retransmit_at, Some(at) if at <= Instant::now());
+            if !due {
+                continue;
+            }
+
+            tcb.retransmits += 1;
+            if tcb.retransmits > MAX_RETRANSMITS {
+                drop(tcb);
+                return self.reset();
+            }
+            tcb.retransmit_at = Some(Instant::now() + RETRANSMIT_TIMEOUT * (1 << tcb.retransmits));
+            match tcb.state {
+                State::SynSent => self.send(&tcb, tcb.snd_una, TCP_SYN, &[]),
+                State::SynReceived => self.send(&tcb, tcb.snd_una, TCP_SYN | TCP_ACK, &[]),
+                State::Established => {
+                    tcb.snd_nxt = tcb.snd_una;
+                    if !tcb.fin_acked {
+                        tcb.fin_sent = false;
+                    }
+                    self.output(&mut tcb);
+                }
+                State::Connecting | State::Closed => {}
+            }
+        }
+    }
+
+    /// the window the guest may fill
+    fn window(&self) -> u16 {
+        RECEIVE_WINDOW.saturating_sub(self.to_host_pending.load(Ordering::SeqCst)) as u16
+    }
+
+    fn send(&self, tcb: &Tcb, seq: u32, flags: u8, payload: &[u8]) {
+        let header = TcpHeader {
+            seq,
+            ack: tcb.rcv_nxt,
+            flags,
+            window: self.window(),
+        };
+        let (guest, remote) = self.key;
+        self.stack.send_ip(&tcp(remote, guest, &header, payload));
+    }
+
+    /// send as much host data as the guest's window allows
+    fn output(&self, tcb: &mut Tcb) {
+        if tcb.state != State::Established {
+            return;
+        }
+        loop {
+            let sent = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+            let window = tcb.window as usize;
+            if sent >= tcb.send_buffer.len() || sent >= window {
+                break;
+            }
+            let len = (tcb.send_buffer.len() - sent)
+                .min(window - sent)
+                .min(usize::from(TCP_MSS));
+            let payload = tcb
+                .send_buffer
+                .range(sent..sent + len)
+                .copied()
+                .collect::<Vec<_>>();
+            self.send(tcb, tcb.snd_nxt, TCP_ACK | TCP_PSH, &payload);
+            tcb.advance(len as u32);
+        }
+
+        let sent = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+        if tcb.host_eof && !tcb.fin_sent && sent == tcb.send_buffer.len() {
+            self.send(tcb, tcb.snd_nxt, TCP_FIN | TCP_ACK, &[]);
+            tcb.advance(1);
+            tcb.fin_sent = true;
+        }
+    }
+
+    /// a segment from the guest
+    pub(super) fn input(&self, segment: &Tcp) {
+        let header = &segment.header;
+        let mut tcb = self.tcb.lock().unwrap();
+        if header.flags & TCP_RST != 0 {
+            drop(tcb);
+            return self.close();
+        }
+
+        match tcb.state {
+            State::Connecting | State::Closed => return,
+            State::SynSent => {
+                if header.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK
+                    && header.ack == tcb.snd_nxt
+                {
+                    tcb.state = State::Established;
+                    tcb.rcv_nxt = header.seq.wrapping_add(1);
+                    tcb.snd_una = header.ack;
+                    tcb.window = u32::from(header.window);
+                    tcb.retransmit_at = None;
+                    tcb.retransmits = 0;
+                    self.send(&tcb, tcb.snd_nxt, TCP_ACK, &[]);
+                    self.output(&mut tcb);
+                }
+                return;
+            }
+            State::SynReceived => {
+                if header.flags & TCP_SYN != 0 {
+                    // our syn-ack got lost
+                    return self.send(&tcb, tcb.snd_una, TCP_SYN | TCP_ACK, &[]);
+                }
+                if header.flags & TCP_ACK == 0 || header.ack != tcb.snd_nxt {
+                    return;
+                }
+                tcb.state = State::Established;
+                tcb.snd_una = header.ack;
+                tcb.retransmit_at = None;
+                tcb.retransmits = 0;
+            }
+            State::Established => {}
+        }
+
+        if header.flags & TCP_ACK != 0 {
+            self.acknowledge(&mut tcb, header);
+        }
+        self.receive(&mut tcb, segment);
+        self.output(&mut tcb);
+
+        if tcb.guest_fin && tcb.fin_acked {
+            drop(tcb);
+            self.close();
+        }
+    }
+
+    fn acknowledge(&self, tcb: &mut Tcb, header: &TcpHeader) {
+        tcb.window = u32::from(header.window);
+        let acked = header.ack.wrapping_sub(tcb.snd_una);
+        if acked == 0 || acked > tcb.snd_max.wrapping_sub(tcb.snd_una) {
+            return;
+        }
+
+        let data = (acked as usize).min(tcb.send_buffer.len());
+        tcb.send_buffer.drain(..data);
+        tcb.snd_una = header.ack;
+        if seq_lt(tcb.snd_nxt, tcb.snd_una) {
+            tcb.snd_nxt = tcb.snd_una;
+        }
+        if tcb.fin_sent && header.ack == tcb.snd_max {
+            tcb.fin_acked = true;
+        }
+        tcb.retransmits = 0;
+        tcb.retransmit_at =
+            (tcb.snd_una != tcb.snd_max).then(|| Instant::now() + RETRANSMIT_TIMEOUT);
+        self.changed.notify_all();
+    }
+
+    /// pass in-order guest data on to the host
+    fn receive(&self, tcb: &mut Tcb, segment: &Tcp) {
+        let fin = segment.header.flags & TCP_FIN != 0;
+        if segment.payload.is_empty() && !fin {
+            return;
+        }
+
+        // skip what the guest retransmitted, anything out of order is dropped
+        let skip = tcb.rcv_nxt.wrapping_sub(segment.header.seq) as usize;
+        if !tcb.guest_fin && skip <= segment.payload.len() {
+            let data = &segment.payload[skip..];
+            let to_host = self.to_host.lock().unwrap();
+            if let Some(to_host) = to_host.as_ref() {
+                if !data.is_empty() {
+                    self.to_host_pending.fetch_add(data.len(), Ordering::SeqCst);
+                    let _ = to_host.send(Some(data.to_vec()));
+                    tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(data.len() as u32);
+                }
+                if fin {
+                    let _ = to_host.send(None);
+                    tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(1);
+                    tcb.guest_fin = true;
+                }
+            }
+        }
+        // ack everything, duplicates tell the guest what is missing
+        self.send(tcb, tcb.snd_nxt, TCP_ACK, &[]);
+    }
+
+    /// abort the connection on both sides
+    fn reset(&self) {
+        let tcb = self.tcb.lock().unwrap();
+        if tcb.state != State::Closed {
+            self.send(&tcb, tcb.snd_nxt, TCP_RST | TCP_ACK, &[]);
+        }
+        drop(tcb);
+        self.close();
+    }
+
+    fn close(&self) {
+        let mut tcb = self.tcb.lock().unwrap();
+        tcb.state = State::Closed;
+        drop(tcb);
+        self.changed.notify_all();
+
+        self.stack.tcp.lock().unwrap().remove(&self.key);
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        self.to_host.lock().unwrap().take();
+    }
+}
+
+/// answer a segment for a connection that does not exist
+pub(super) fn reset(stack: &Stack, key: TcpKey, segment: &Tcp) {
+    let header = &segment.header;
+    if header.flags & TCP_RST != 0 {
+        return;
+    }
+    let reply = if header.flags & TCP_ACK != 0 {
+        TcpHeader {
+            seq: header.ack,
+            flags: TCP_RST,
+            ..TcpHeader::default()
+        }
+    } else {
+        TcpHeader {
+            ack: header.seq.wrapping_add(segment.len()),
+            flags: TCP_RST | TCP_ACK,
+            ..TcpHeader::default()
+        }
+    };
+    let (guest, remote) = key;
+    stack.send_ip(&tcp(remote, guest, &reply, &[]));
+}
@@ -0,0 +1,429 @@
+//! copy-on-write overlays for block devices
+//!
+//! reads fall through to a base image until a cluster is written. overlay
+//! files start with a header naming the base image and one byte of state per
+//! cluster, the clusters themselves live in a sparse area after that:
+//!
+//! ```text
+//! 0     magic "WASMCOW\0"
+//! 8     cluster size, u32
+//! 12    disk size, u64
+//! 20    length of the base image path, u32
+//! 24    base image path
+//! 4096  cluster states
+//! ...   clusters, aligned to the cluster size
+//! ```
+
+use super::block::{Disk, RawDisk};
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{fallocate, FallocateFlags};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    os::{
+        fd::AsRawFd,
+        unix::{ffi::OsStrExt, fs::FileExt},
+    },
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 8] = b"WASMCOW\0";
+const CLUSTER_SIZE: u64 = 0x10000;
+const MAP_OFFSET: u64 = 4096;
+
+/// read from the base image
+const CLUSTER_BASE: u8 = 0;
+/// read from the overlay
+const CLUSTER_DATA: u8 = 1;
+/// discarded, reads as zeroes
+const CLUSTER_ZERO: u8 = 2;
+
+enum Clusters {
+    File { file: File, data_offset: u64 },
+    Memory(HashMap<u64, Vec<u8>>),
+}
+
+/// a writable view of a read-only base image
+pub struct Overlay {
+    base: RawDisk,
+    clusters: Clusters,
+    map: Vec<u8>,
+}
+
+fn clusters_for(size: u64) -> u64 {
+    size.div_ceil(CLUSTER_SIZE)
+}
+
+fn data_offset(clusters: u64) -> u64 {
+    (MAP_OFFSET + clusters).next_multiple_of(CLUSTER_SIZE)
+}
+
+impl Overlay {
+    /// an overlay that is discarded when the runner exits
+    pub fn in_memory(base: &Path) -> Result<Self> {
+        let base = RawDisk::open(base, true)?;
+        let map = vec![CLUSTER_BASE; clusters_for(base.size()) as usize];
+        Ok(Self {
+            base,
+            clusters: Clusters::Memory(HashMap::new()),
+            map,
+        })
+    }
+
+    /// create a fresh overlay file at `path`, replacing an existing one
+    pub fn create(base_path: &Path, path: &Path) -> Result<Self> {
+        let base = RawDisk::open(base_path, true)?;
+        let base_path = base_path
+            .canonicalize()
+            .with_context(|| format!("while resolving {}", base_path.display()))?;
+        let base_path = base_path.as_os_str().as_bytes();
+        if 24 + base_path.len() as u64 > MAP_OFFSET {
+            bail!("the base image path is too long for an overlay");
+        }
+
+        let clusters = clusters_for(base.size());
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("while creating {}", path.display()))?;
+
+        let mut header = Vec::with_capacity(MAP_OFFSET as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&(CLUSTER_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&base.size().to_le_bytes());
+        header.extend_from_slice(&(base_path.len() as u32).to_le_bytes());
+        header.extend_from_slice(base_path);
+        file.write_all_at(&header, 0)?;
+
+        let data_offset = data_offset(clusters);
+        // the clusters stay sparse until they are written
+        file.set_len(data_offset + clusters * CLUSTER_SIZE)?;
+
+        Ok(Self {
+            base,
+            clusters: Clusters::File { file, data_offset },
+            map: vec![CLUSTER_BASE; clusters as usize],
+        })
+    }
+
+    fn read_cluster(&self, cluster: u64, buf: &mut [u8], offset: u64) -> Result<()> {
+        match &self.clusters {
+            Clusters::File { file, data_offset } => {
+                file.read_exact_at(buf, data_offset + cluster * CLUSTER_SIZE + offset)?;
+            }
+            Clusters::Memory(clusters) => {
+                let data = &clusters[&cluster][offset as usize..][..buf.len()];
+                buf.copy_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_cluster(&mut self, cluster: u64, buf: &[u8], offset: u64) -> Result<()> {
+        match &mut self.clusters {
+            Clusters::File { file, data_offset } => {
+                file.write_all_at(buf, *data_offset + cluster * CLUSTER_SIZE + offset)?;
+            }
+            Clusters::Memory(clusters) => {
+                let data = clusters
+                    .entry(cluster)
+                    .or_insert_with(|| vec![0; CLUSTER_SIZE as usize]);
+                data[offset as usize..][..buf.len()].copy_from_slice(buf);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_state(&mut self, cluster: u64, state: u8) -> Result<()> {
+        self.map[cluster as usize] = state;
+        if let Clusters::File { file, .. } = &self.clusters {
+            file.write_all_at(&[state], MAP_OFFSET + cluster)?;
+        }
+        Ok(())
+    }
+
+    /// the bytes of a cluster that is not in the overlay yet
+    fn cluster_contents(&mut self, cluster: u64) -> Result<Vec<u8>> {
+        let start = cluster * CLUSTER_SIZE;
+        let len = CLUSTER_SIZE.min(self.base.size() - start);
+        let mut contents = vec![0; CLUSTER_SIZE as usize];
+        if self.map[cluster as usize] == CLUSTER_BASE {
+            self.base.read_at(&mut contents[..len as usize], start)?;
+        }
+        Ok(contents)
+    }
+
+    /// split `len` bytes at `offset` into (cluster, offset in cluster, len)
+    fn pieces(offset: u64, len: u64) -> impl Iterator<Item = (u64, u64, u64)> {
+        let end = offset + len;
+        let mut offset = offset;
+        std::iter::from_fn(move || {
+            if offset >= end {
+                return None;
+            }
+            let cluster = offset / CLUSTER_SIZE;
+            let start = offset % CLUSTER_SIZE;
+            let len = (CLUSTER_SIZE - start).min(end - offset);
+            offset += len;
+            Some((cluster, start, len))
+        })
+    }
+}
+
+impl Disk for Overlay {
+    fn size(&self) -> u64 {
+        self.base.size()
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let mut buf = buf;
+        for (cluster, start, len) in Self::pieces(offset, buf.len() as u64) {
+            let (piece, rest) = buf.split_at_mut(len as usize);
+            match self.map[cluster as usize] {
+                CLUSTER_DATA => self.read_cluster(cluster, piece, start)?,
+                CLUSTER_ZERO => piece.fill(0),
+                _ => self.base.read_at(piece, cluster * CLUSTER_SIZE + start)?,
+            }
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        let mut buf = buf;
+        for (cluster, start, len) in Self::pieces(offset, buf.len() as u64) {
+            let (piece, rest) = buf.split_at(len as usize);
+            if self.map[cluster as usize] == CLUSTER_DATA {
+                self.write_cluster(cluster, piece, start)?;
+            } else {
+                // copy the whole cluster on its first write
+                let mut contents = self.cluster_contents(cluster)?;
+                contents[start as usize..][..piece.len()].copy_from_slice(piece);
+                self.write_cluster(cluster, &contents, 0)?;
+                self.set_state(cluster, CLUSTER_DATA)?;
+            }
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Clusters::File { file, .. } = &self.clusters {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> Result<()> {
+        for (cluster, start, len) in Self::pieces(offset, len) {
+            if len < CLUSTER_SIZE {
+                self.write_at(&vec![0; len as usize], cluster * CLUSTER_SIZE + start)?;
+                continue;
+            }
+            match &mut self.clusters {
+                Clusters::File { file, data_offset } => {
+                    fallocate(
+                        file.as_raw_fd(),
+                        FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                        (*data_offset + cluster * CLUSTER_SIZE).try_into()?,
+                        CLUSTER_SIZE.try_into()?,
+                    )?;
+                }
+                Clusters::Memory(clusters) => {
+                    clusters.remove(&cluster);
+                }
+            }
+            self.set_state(cluster, CLUSTER_ZERO)?;
+        }
+        Ok(())
+    }
+}
+
+/// write every cluster of the overlay file at `path` back into its base image
+///
+/// the overlay is empty afterwards, so committing twice is harmless.
+pub fn commit(path: &Path) -> Result<()> {
+    let overlay = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("while opening {}", path.display()))?;
+
+    let mut header = vec![0; MAP_OFFSET as usize];
+    overlay.read_exact_at(&mut header, 0)?;
+    if &header[0..8] != MAGIC {
+        bail!("{} is not an overlay", path.display());
+    }
+    let cluster_size = u64::from(u32::from_le_bytes(header[8..12].try_into()?));
+    let size = u64::from_le_bytes(header[12..20].try_into()?);
+    let path_len = u32::from_le_bytes(header[20..24].try_into()?) as usize;
+    let Some(base_path) = header[24..].get(..path_len) else {
+        bail!("{} has a corrupt header", path.display());
+    };
+    let base_path = PathBuf::from(std::ffi::OsStr::from_bytes(base_path));
+    if cluster_size != CLUSTER_SIZE {
+        bail!("unsupported cluster size {cluster_size}");
+    }
+
+    let mut base = RawDisk::open(&base_path, false)?;
+    if base.size() != size {
+        bail!(
+            "{} changed size since the overlay was created",
+            base_path.display()
+        );
+    }
+
+    let clusters = clusters_for(size);
+    let mut map = vec![0; clusters as usize];
+    overlay.read_exact_at(&mut map, MAP_OFFSET)?;
+    let data_offset = data_offset(clusters);
+
+    let mut buf = vec![0; CLUSTER_SIZE as usize];
+    for (cluster, &state) in map.iter().enumerate() {
+        let start = cluster as u64 * CLUSTER_SIZE;
+        let len = CLUSTER_SIZE.min(size - start);
+        match state {
+            CLUSTER_DATA => {
+                let buf = &mut buf[..len as usize];
+                overlay.read_exact_at(buf, data_offset + start)?;
+                base.write_at(buf, start)?;
+            }
+            CLUSTER_ZERO => base.discard(start, len)?,
+            _ => {}
+        }
+    }
+    base.flush()?;
+
+    map.fill(CLUSTER_BASE);
+    overlay.write_all_at(&map, MAP_OFFSET)?;
+    overlay.sync_data()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs;
+
+    /// two and a half clusters, so the last one is short
+    const BASE_SIZE: u64 = CLUSTER_SIZE * 5 / 2;
+
+    /// a base image in a fresh temporary directory
+    struct Fixture {
+        dir: TempDir,
+        /// what the disk should read as
+        expected: Vec<u8>,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = TempDir::new(&format!("overlay-{name}"));
+            let expected = (0..BASE_SIZE)
+                .map(|at| (at % 251) as u8)
+                .collect::<Vec<_>>();
+            fs::write(dir.join("base.img"), &expected).unwrap();
+            Self { dir, expected }
+        }
+
+        fn base(&self) -> PathBuf {
+            self.dir.join("base.img")
+        }
+
+        fn write(&mut self, disk: &mut impl Disk, data: &[u8], offset: u64) {
+            disk.write_at(data, offset).unwrap();
+            self.expected[offset as usize..][..data.len()].copy_from_slice(data);
+        }
+
+        fn discard(&mut self, disk: &mut impl Disk, offset: u64, len: u64) {
+            disk.discard(offset, len).unwrap();
+            self.expected[offset as usize..][..len as usize].fill(0);
+        }
+
+        /// write across both cluster boundaries and discard a whole cluster
+        /// and a part of one
+        fn scribble(&mut self, disk: &mut impl Disk) {
+            self.write(disk, &[0xaa; 10], CLUSTER_SIZE - 5);
+            self.write(disk, &[0xbb; 3], CLUSTER_SIZE * 2 - 1);
+            self.write(disk, &[0xcc; 7], CLUSTER_SIZE - 2);
+            self.write(disk, &[0xdd; 4], BASE_SIZE - 4);
+            self.discard(disk, 0, CLUSTER_SIZE);
+            self.discard(disk, CLUSTER_SIZE + 100, 50);
+        }
+
+        fn check(&self, disk: &mut impl Disk) {
+            let mut contents = vec![0x55; BASE_SIZE as usize];
+            disk.read_at(&mut contents, 0).unwrap();
+            assert!(contents == self.expected);
+
+            // reads that start and end inside clusters
+            let mut piece = vec![0x55; CLUSTER_SIZE as usize];
+            disk.read_at(&mut piece, CLUSTER_SIZE / 2).unwrap();
+            assert!(piece[..] == self.expected[CLUSTER_SIZE as usize / 2..][..piece.len()]);
+        }
+
+        fn base_contents(&self) -> Vec<u8> {
+            fs::read(self.base()).unwrap()
+        }
+    }
+
+    #[test]
+    fn memory_overlays_copy_on_write() {
+        let mut fixture = Fixture::new("memory");
+        let original = fixture.base_contents();
+        let mut overlay = Overlay::in_memory(&fixture.base()).unwrap();
+        assert_eq!(overlay.size(), BASE_SIZE);
+        fixture.check(&mut overlay);
+
+        fixture.scribble(&mut overlay);
+        fixture.check(&mut overlay);
+        assert!(fixture.base_contents() == original);
+    }
+
+    #[test]
+    fn file_overlays_copy_on_write() {
+        let mut fixture = Fixture::new("file");
+        let original = fixture.base_contents();
+        let mut overlay = Overlay::create(&fixture.base(), &fixture.dir.join("cow")).unwrap();
+        fixture.check(&mut overlay);
+
+        fixture.scribble(&mut overlay);
+        overlay.flush().unwrap();
+        fixture.check(&mut overlay);
+        assert!(fixture.base_contents() == original);
+    }
+
+    #[test]
+    fn commits_write_back_into_the_base() {
+        let mut fixture = Fixture::new("commit");
+        let path = fixture.dir.join("cow");
+        let mut overlay = Overlay::create(&fixture.base(), &path).unwrap();
+        fixture.scribble(&mut overlay);
+        overlay.flush().unwrap();
+        drop(overlay);
+
+        commit(&path).unwrap();
+        assert!(fixture.base_contents() == fixture.expected);
+
+        // the overlay is empty now, a second commit leaves the base alone
+        fs::write(fixture.base(), [1; BASE_SIZE as usize]).unwrap();
+        commit(&path).unwrap();
+        assert!(fixture.base_contents() == [1; BASE_SIZE as usize]);
+    }
+
+    #[test]
+    fn commits_check_the_header() {
+        let fixture = Fixture::new("header");
+        let path = fixture.dir.join("cow");
+        fs::write(&path, [0; MAP_OFFSET as usize]).unwrap();
+        assert!(commit(&path).is_err());
+
+        Overlay::create(&fixture.base(), &path).unwrap();
+        fs::write(fixture.base(), [0; 10]).unwrap();
+        assert!(commit(&path).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+mod server;
+mod wire;
+
+use super::{Interrupt, Queue, VirtioDevice};
+use crate::memory::GuestMemory;
+use anyhow::{bail, Context, Result};
+use server::{Server, HEADER_LEN};
+use std::{path::PathBuf, str::FromStr};
+
+const VIRTIO_ID_9P: u32 = 9;
+
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+const QUEUE_SIZE: u16 = 128;
+/// what fits into the configuration space of a register window
+const MAX_TAG_LEN: usize = 254;
+
+/// the value of a `--share` option, `<tag>=<dir>[,ro]`
+#[derive(Clone, Debug)]
+pub struct ShareOptions {
+    pub tag: String,
+    pub path: PathBuf,
+    pub readonly: bool,
+}
+
+impl FromStr for ShareOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(options: &str) -> Result<Self> {
+        let (share, flags) = match options.split_once(',') {
+            Some((share, flags)) => (share, Some(flags)),
+            None => (options, None),
+        };
+        let Some((tag, path)) = share.split_once('=') else {
+            bail!("expected <tag>=<dir>, got {share}");
+        };
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            bail!("invalid share tag {tag}");
+        }
+        let readonly = match flags {
+            None => false,
+            Some("ro") => true,
+            Some(flags) => bail!("unknown share option {flags}"),
+        };
+        Ok(Self {
+            tag: tag.to_string(),
+            path: PathBuf::from(path),
+            readonly,
+        })
+    }
+}
+
+struct Active {
+    memory: GuestMemory,
+    interrupt: Interrupt,
+    queue: Queue,
+}
+
+/// virtio-9p, a host directory the guest mounts with `mount -t 9p <tag>`
+pub struct Share {
+    tag: String,
+    server: Server,
+    active: Option<Active>,
+}
+
+impl Share {
+    pub fn new(options: &ShareOptions) -> Result<Self> {
+        let root = options
+            .path
+            .canonicalize()
+            .with_context(|| format!("while opening {}", options.path.display()))?;
+        if !root.is_dir() {
+            bail!("{} is not a directory", root.display());
+        }
+        Ok(Self {
+            tag: options.tag.clone(),
+            server: Server::new(&root, options.readonly)
+                .with_context(|| format!("while opening {}", root.display()))?,
+            active: None,
+        })
+    }
+
+    fn process_requests(&mut self) -> Result<()> {
+        let Some(active) = &mut self.active else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while let Some(request) = active.queue.pop(&active.memory)? {
+            let message = request.read_all(&active.memory)?;
+            let reply_len = request.writable_len() as usize;
+            let reply = self.server.handle(&message, reply_len);
+            // a reply that does not fit is lost, the driver sizes its buffers by msize
+            let len = if reply.len() >= HEADER_LEN && reply.len() <= reply_len {
+                request.write_all(&active.memory, &reply)?
+            } else {
+                0
+            };
+            active.queue.add_used(&active.memory, request.head, len)?;
+            used = true;
+        }
+
+        if used {
+            active.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Share {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        config
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        _features: u64,
+    ) -> Result<()> {
+        let Some(queue) = queues.pop() else {
+            bail!("virtio-9p needs a request queue");
+        };
+        self.active = Some(Active {
+            memory,
+            interrupt,
+            queue,
+        });
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, _queue: usize) -> Result<()> {
+        self.process_requests()
+    }
+
+    fn reset(&mut self) {
+        self.active = None;
+    }
+}
@@ -0,0 +1,992 @@
+//! a 9P2000.L file server for one host directory
+//!
+//! fids are `O_PATH` descriptors of files in the shared directory, and every
+//! path the server resolves starts at one of them in `/proc/self/fd`, which
+//! jumps straight to the file. a fid follows its file through renames, a walk
+//! takes one component at a time without following symlinks and ".." stops at
+//! the root, so nothing the guest does reaches outside the directory. the
+//! guest resolves links itself with readlink.
+
+use super::wire::{Qid, Reader, Writer, QID_LEN};
+use nix::{
+    errno::Errno as NixErrno,
+    fcntl::{readlinkat, AtFlags, OFlag},
+    sys::{
+        stat::{mknod, utimensat, Mode, SFlag, UtimensatFlags},
+        statvfs::fstatvfs,
+        time::TimeSpec,
+    },
+    unistd::linkat,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata, OpenOptions},
+    io,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{
+                chown, symlink, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+            },
+        },
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// an errno for Rlerror
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Errno(pub u32);
+
+pub(super) const EINVAL: Errno = Errno(22);
+const EIO: Errno = Errno(5);
+const EBADF: Errno = Errno(9);
+const EISDIR: Errno = Errno(21);
+const EROFS: Errno = Errno(30);
+const ELOOP: Errno = Errno(40);
+const EOPNOTSUPP: Errno = Errno(95);
+
+impl From<io::Error> for Errno {
+    fn from(err: io::Error) -> Self {
+        err.raw_os_error().map_or(EIO, |errno| Errno(errno as u32))
+    }
+}
+
+impl From<NixErrno> for Errno {
+    fn from(errno: NixErrno) -> Self {
+        Errno(errno as u32)
+    }
+}
+
+const TLERROR: u8 = 6;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const VERSION: &str = "9P2000.L";
+/// size, type and tag
+pub(super) const HEADER_LEN: usize = 7;
+const MAX_MSIZE: u32 = 512 * 1024;
+
+/// the linux open flags 9P2000.L uses, whatever the host's are
+const L_O_WRONLY: u32 = 0o1;
+const L_O_RDWR: u32 = 0o2;
+const L_O_ACCMODE: u32 = 0o3;
+const L_O_EXCL: u32 = 0o200;
+const L_O_TRUNC: u32 = 0o1000;
+const L_O_APPEND: u32 = 0o2000;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// every field of Rgetattr except btime, gen and data_version
+const GETATTR_BASIC: u64 = 0x7ff;
+
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+/// the directory a fid was walked from and the file's name in it
+type Parent = Option<(Arc<File>, String)>;
+
+struct Fid {
+    /// usually an `O_PATH` descriptor
+    node: Arc<File>,
+    /// none for the root and after ".."
+    parent: Parent,
+    file: Option<File>,
+    /// the directory listing, taken when reading from offset 0
+    entries: Option<Vec<(Qid, u8, Vec<u8>)>>,
+}
+
+pub(super) struct Server {
+    root: Arc<File>,
+    /// the device and inode of the root, where ".." stops
+    root_id: (u64, u64),
+    readonly: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+fn metadata(path: &Path) -> Result<Metadata, Errno> {
+    Ok(fs::symlink_metadata(path)?)
+}
+
+/// a path that resolves to the file behind `fd` itself, even a symlink. a
+/// name joined to it is looked up in that directory.
+fn proc_path(fd: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// an `O_PATH` descriptor of `path`, a symlink itself rather than its target
+fn open_path(path: &Path) -> Result<File, Errno> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .custom_flags((OFlag::O_PATH | OFlag::O_NOFOLLOW).bits())
+        .open(path)?)
+}
+
+fn dirent_type(metadata: &Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        4
+    } else if file_type.is_symlink() {
+        10
+    } else if file_type.is_fifo() {
+        1
+    } else if file_type.is_char_device() {
+        2
+    } else if file_type.is_block_device() {
+        6
+    } else if file_type.is_socket() {
+        12
+    } else {
+        8
+    }
+}
+
+/// a single path component, anything that could leave the directory is EINVAL
+fn component(name: &str) -> Result<&str, Errno> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(EINVAL);
+    }
+    Ok(name)
+}
+
+impl Server {
+    pub fn new(root: &Path, readonly: bool) -> io::Result<Self> {
+        let root = OpenOptions::new()
+            .read(true)
+            .custom_flags((OFlag::O_PATH | OFlag::O_DIRECTORY).bits())
+            .open(root)?;
+        let metadata = root.metadata()?;
+        Ok(Self {
+            root: Arc::new(root),
+            root_id: (metadata.dev(), metadata.ino()),
+            readonly,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// the largest read that fits into `reply_len` bytes
+    fn max_io(&self, reply_len: usize) -> usize {
+        (self.msize as usize)
+            .min(reply_len)
+            .saturating_sub(HEADER_LEN + 4)
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid, Errno> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> Result<&mut Fid, Errno> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    fn writable(&self) -> Result<(), Errno> {
+        if self.readonly {
+            return Err(EROFS);
+        }
+        Ok(())
+    }
+
+    fn child(&self, dfid: u32, name: &str) -> Result<PathBuf, Errno> {
+        Ok(proc_path(&self.fid(dfid)?.node).join(component(name)?))
+    }
+
+    fn is_root(&self, node: &File) -> Result<bool, Errno> {
+        let metadata = node.metadata()?;
+        Ok((metadata.dev(), metadata.ino()) == self.root_id)
+    }
+
+    /// the parent directory of `node`, itself for the root
+    fn parent_dir(&self, node: &Arc<File>) -> Result<Arc<File>, Errno> {
+        if self.is_root(node)? {
+            return Ok(node.clone());
+        }
+        Ok(Arc::new(open_path(&proc_path(node).join(".."))?))
+    }
+
+    /// walk from `node` to `name`, returns the file, where it was walked from
+    /// and its metadata
+    fn step(
+        &self,
+        node: &Arc<File>,
+        parent: &Parent,
+        name: &str,
+    ) -> Result<(Arc<File>, Parent, Metadata), Errno> {
+        let (next, parent) = match name {
+            "." => (node.clone(), parent.clone()),
+            ".." => (self.parent_dir(node)?, None),
+            name => {
+                let next = open_path(&proc_path(node).join(component(name)?))?;
+                (Arc::new(next), Some((node.clone(), name.to_string())))
+            }
+        };
+        let metadata = next.metadata()?;
+        Ok((next, parent, metadata))
+    }
+
+    /// handle one request, returns the complete reply
+    pub fn handle(&mut self, request: &[u8], reply_len: usize) -> Vec<u8> {
+        let mut reader = Reader::new(request);
+        let (Ok(_), Ok(kind), Ok(tag)) = (reader.u32(), reader.u8(), reader.u16()) else {
+            return Vec::new();
+        };
+
+        let mut reply = Writer::default();
+        let (kind, reply) = match self.dispatch(kind, &mut reader, &mut reply, reply_len) {
+            Ok(()) => (kind + 1, reply.data),
+            Err(Errno(errno)) => (TLERROR + 1, errno.to_le_bytes().to_vec()),
+        };
+
+        let mut message = Vec::with_capacity(HEADER_LEN + reply.len());
+        message.extend_from_slice(&((HEADER_LEN + reply.len()) as u32).to_le_bytes());
+        message.push(kind);
+        message.extend_from_slice(&tag.to_le_bytes());
+        message.extend_from_slice(&reply);
+        message
+    }
+
+    fn dispatch(
+        &mut self,
+        kind: u8,
+        request: &mut Reader,
+        reply: &mut Writer,
+        reply_len: usize,
+    ) -> Result<(), Errno> {
+        match kind {
+            TVERSION => self.version(request, reply),
+            TATTACH => self.attach(request, reply),
+            TWALK => self.walk(request, reply),
+            TGETATTR => self.getattr(request, reply),
+            TSETATTR => self.setattr(request),
+            TLOPEN => self.lopen(request, reply),
+            TLCREATE => self.lcreate(request, reply),
+            TREAD => self.read(request, reply, reply_len),
+            TWRITE => self.write(request, reply),
+            TREADDIR => self.readdir(request, reply, reply_len),
+            TCLUNK => {
+                self.fids.remove(&request.u32()?).ok_or(EBADF)?;
+                Ok(())
+            }
+            TREMOVE => self.remove(request),
+            TSTATFS => self.statfs(request, reply),
+            TMKDIR => self.mkdir(request, reply),
+            TSYMLINK => self.symlink(request, reply),
+            TMKNOD => self.mknod(request, reply),
+            TREADLINK => self.readlink(request, reply),
+            TLINK => self.link(request),
+            TRENAME => self.rename(request),
+            TRENAMEAT => self.renameat(request),
+            TUNLINKAT => self.unlinkat(request),
+            TFSYNC => {
+                if let Some(file) = &self.fid(request.u32()?)?.file {
+                    file.sync_all()?;
+                }
+                Ok(())
+            }
+            TLOCK => {
+                // locks only matter between guests, and there is one
+                self.fid(request.u32()?)?;
+                reply.u8(LOCK_SUCCESS);
+                Ok(())
+            }
+            TGETLOCK => self.getlock(request, reply),
+            // requests complete before the next one is read
+            TFLUSH => Ok(()),
+            // including xattrs, the guest copes without them
+            _ => Err(EOPNOTSUPP),
+        }
+    }
+
+    fn version(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let msize = request.u32()?;
+        let version = request.string()?;
+        self.fids.clear();
+        self.msize = msize.min(MAX_MSIZE);
+        reply.u32(self.msize);
+        reply.string(
+            if version == VERSION {
+                VERSION
+            } else {
+                "unknown"
+            }
+            .as_bytes(),
+        );
+        Ok(())
+    }
+
+    fn attach(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let metadata = self.root.metadata()?;
+        self.fids.insert(
+            fid,
+            Fid {
+                node: self.root.clone(),
+                parent: None,
+                file: None,
+                entries: None,
+            },
+        );
+        reply.qid(&Qid::new(&metadata));
+        Ok(())
+    }
+
+    fn walk(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let newfid = request.u32()?;
+        let names = (0..request.u16()?)
+            .map(|_| request.string())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let start = self.fid(fid)?;
+        let (mut node, mut parent) = (start.node.clone(), start.parent.clone());
+        let mut qids = Vec::new();
+        for (index, name) in names.iter().enumerate() {
+            let (next, next_parent, metadata) = match self.step(&node, &parent, name) {
+                Ok(step) => step,
+                Err(err) if index == 0 => return Err(err),
+                Err(_) => break,
+            };
+            if metadata.is_symlink() && index + 1 < names.len() {
+                if index == 0 {
+                    return Err(ELOOP);
+                }
+                break;
+            }
+            qids.push(Qid::new(&metadata));
+            (node, parent) = (next, next_parent);
+        }
+
+        // a partial walk does not create newfid
+        if qids.len() == names.len() {
+            self.fids.insert(
+                newfid,
+                Fid {
+                    node,
+                    parent,
+                    file: None,
+                    entries: None,
+                },
+            );
+        }
+        reply.u16(qids.len() as u16);
+        for qid in &qids {
+            reply.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn getattr(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let metadata = self.fid(request.u32()?)?.node.metadata()?;
+        reply.u64(GETATTR_BASIC);
+        reply.qid(&Qid::new(&metadata));
+        reply.u32(metadata.mode());
+        reply.u32(metadata.uid());
+        reply.u32(metadata.gid());
+        reply.u64(metadata.nlink());
+        reply.u64(metadata.rdev());
+        reply.u64(metadata.size());
+        reply.u64(metadata.blksize());
+        reply.u64(metadata.blocks());
+        for (sec, nsec) in [
+            (metadata.atime(), metadata.atime_nsec()),
+            (metadata.mtime(), metadata.mtime_nsec()),
+            (metadata.ctime(), metadata.ctime_nsec()),
+            // btime
+            (0, 0),
+        ] {
+            reply.u64(sec as u64);
+            reply.u64(nsec as u64);
+        }
+        // gen and data_version
+        reply.u64(0);
+        reply.u64(0);
+        Ok(())
+    }
+
+    fn setattr(&mut self, request: &mut Reader) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let valid = request.u32()?;
+        let mode = request.u32()?;
+        let uid = request.u32()?;
+        let gid = request.u32()?;
+        let size = request.u64()?;
+        let atime = (request.u64()?, request.u64()?);
+        let mtime = (request.u64()?, request.u64()?);
+        self.writable()?;
+        let node = &self.fid(fid)?.node;
+        // changes the symlink itself rather than its target, like lchown
+        let path = &proc_path(node);
+        let metadata = node.metadata()?;
+
+        if valid & SETATTR_MODE != 0 {
+            if metadata.is_symlink() {
+                return Err(ELOOP);
+            }
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+            chown(
+                path,
+                (valid & SETATTR_UID != 0).then_some(uid),
+                (valid & SETATTR_GID != 0).then_some(gid),
+            )?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            if metadata.is_symlink() {
+                return Err(ELOOP);
+            }
+            if metadata.is_dir() {
+                return Err(EISDIR);
+            }
+            if !metadata.is_file() {
+                return Err(EINVAL);
+            }
+            OpenOptions::new().write(true).open(path)?.set_len(size)?;
+        }
+        if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+            let time = |bit: u32, set: u32, (sec, nsec): (u64, u64)| match valid & (bit | set) {
+                0 => TimeSpec::UTIME_OMIT,
+                bits if bits & set != 0 => TimeSpec::new(sec as i64, nsec as i64),
+                _ => TimeSpec::UTIME_NOW,
+            };
+            utimensat(
+                None,
+                path,
+                &time(SETATTR_ATIME, SETATTR_ATIME_SET, atime),
+                &time(SETATTR_MTIME, SETATTR_MTIME_SET, mtime),
+                UtimensatFlags::FollowSymlink,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn open_options(&self, flags: u32) -> Result<OpenOptions, Errno> {
+        let mut options = OpenOptions::new();
+        match flags & L_O_ACCMODE {
+            L_O_WRONLY => options.write(true),
+            L_O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        if flags & L_O_ACCMODE != 0 || flags & L_O_TRUNC != 0 {
+            self.writable()?;
+        }
+        options
+            .append(flags & L_O_APPEND != 0)
+            .truncate(flags & L_O_TRUNC != 0);
+        Ok(options)
+    }
+
+    fn lopen(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let flags = request.u32()?;
+        let node = &self.fid(fid)?.node;
+        let metadata = node.metadata()?;
+
+        // directories are only read through readdir, opening a symlink is ELOOP
+        let file = if metadata.is_dir() {
+            None
+        } else {
+            Some(self.open_options(flags)?.open(proc_path(node))?)
+        };
+        let fid = self.fid_mut(fid)?;
+        fid.file = file;
+        fid.entries = None;
+        reply.qid(&Qid::new(&metadata));
+        // iounit, the client falls back to msize
+        reply.u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let name = request.string()?;
+        let flags = request.u32()?;
+        let mode = request.u32()?;
+        let _gid = request.u32()?;
+        self.writable()?;
+        let path = self.child(fid, name)?;
+
+        let mut options = self.open_options(flags)?;
+        if flags & L_O_ACCMODE == 0 {
+            // a file created read-only is still created
+            options.write(true);
+        }
+        if flags & L_O_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(true);
+        }
+        let file = options
+            .mode(mode & 0o7777)
+            .custom_flags(OFlag::O_NOFOLLOW.bits())
+            .open(&path)?;
+
+        let qid = Qid::new(&file.metadata()?);
+        let node = Arc::new(file.try_clone()?);
+        let fid = self.fid_mut(fid)?;
+        fid.parent = Some((std::mem::replace(&mut fid.node, node), name.to_string()));
+        fid.file = Some(file);
+        fid.entries = None;
+        reply.qid(&qid);
+        reply.u32(0);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        request: &mut Reader,
+        reply: &mut Writer,
+        reply_len: usize,
+    ) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let offset = request.u64()?;
+        let count = (request.u32()? as usize).min(self.max_io(reply_len));
+        let Some(file) = &self.fid(fid)?.file else {
+            return Err(EBADF);
+        };
+
+        let mut buf = vec![0; count];
+        let mut len = 0;
+        while len < count {
+            match file.read_at(&mut buf[len..], offset + len as u64) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        reply.u32(len as u32);
+        reply.bytes(&buf[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let offset = request.u64()?;
+        let count = request.u32()? as usize;
+        let data = request.bytes(count)?;
+        let Some(file) = &self.fid(fid)?.file else {
+            return Err(EBADF);
+        };
+        file.write_all_at(data, offset)?;
+        reply.u32(count as u32);
+        Ok(())
+    }
+
+    fn readdir(
+        &mut self,
+        request: &mut Reader,
+        reply: &mut Writer,
+        reply_len: usize,
+    ) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let offset = request.u64()? as usize;
+        let count = (request.u32()? as usize).min(self.max_io(reply_len));
+
+        if offset == 0 || self.fid(fid)?.entries.is_none() {
+            let node = self.fid(fid)?.node.clone();
+            let mut entries = Vec::new();
+            for (name, dir) in [(".", node.clone()), ("..", self.parent_dir(&node)?)] {
+                let metadata = dir.metadata()?;
+                entries.push((Qid::new(&metadata), 4, name.as_bytes().to_vec()));
+            }
+            for entry in fs::read_dir(proc_path(&node))? {
+                let entry = entry?;
+                // entries that vanished since the listing started
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                let name = entry.file_name().as_bytes().to_vec();
+                entries.push((Qid::new(&metadata), dirent_type(&metadata), name));
+            }
+            self.fid_mut(fid)?.entries = Some(entries);
+        }
+        let fid = self.fid(fid)?;
+
+        let mut data = Writer::default();
+        for (index, (qid, kind, name)) in fid.entries.iter().flatten().enumerate().skip(offset) {
+            let len = QID_LEN + 8 + 1 + 2 + name.len();
+            if data.data.len() + len > count {
+                break;
+            }
+            data.qid(qid);
+            data.u64(index as u64 + 1);
+            data.u8(*kind);
+            data.string(name);
+        }
+        reply.u32(data.data.len() as u32);
+        reply.bytes(&data.data);
+        Ok(())
+    }
+
+    fn remove(&mut self, request: &mut Reader) -> Result<(), Errno> {
+        // the fid is clunked even when the remove fails
+        let fid = self.fids.remove(&request.u32()?).ok_or(EBADF)?;
+        self.writable()?;
+        let Some((dir, name)) = &fid.parent else {
+            return Err(EINVAL);
+        };
+        let path = proc_path(dir).join(name);
+        if fid.node.metadata()?.is_dir() {
+            fs::remove_dir(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn statfs(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let stat = fstatvfs(&*self.fid(request.u32()?)?.node)?;
+        // V9FS_MAGIC
+        reply.u32(0x0102_1997);
+        reply.u32(stat.block_size() as u32);
+        reply.u64(stat.blocks() as u64);
+        reply.u64(stat.blocks_free() as u64);
+        reply.u64(stat.blocks_available() as u64);
+        reply.u64(stat.files() as u64);
+        reply.u64(stat.files_free() as u64);
+        reply.u64(stat.filesystem_id() as u64);
+        reply.u32(stat.name_max() as u32);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let dfid = request.u32()?;
+        let name = request.string()?;
+        let mode = request.u32()?;
+        let _gid = request.u32()?;
+        self.writable()?;
+        let path = self.child(dfid, name)?;
+        fs::create_dir(&path)?;
+        // without the umask
+        let dir = open_path(&path)?;
+        fs::set_permissions(proc_path(&dir), fs::Permissions::from_mode(mode & 0o7777))?;
+        reply.qid(&Qid::new(&dir.metadata()?));
+        Ok(())
+    }
+
+    fn symlink(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let dfid = request.u32()?;
+        let name = request.string()?;
+        let target = request.string()?;
+        let _gid = request.u32()?;
+        self.writable()?;
+        let path = self.child(dfid, name)?;
+        symlink(target, &path)?;
+        reply.qid(&Qid::new(&metadata(&path)?));
+        Ok(())
+    }
+
+    fn mknod(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let dfid = request.u32()?;
+        let name = request.string()?;
+        let mode = request.u32()?;
+        let major = request.u32()?;
+        let minor = request.u32()?;
+        let _gid = request.u32()?;
+        self.writable()?;
+        let path = self.child(dfid, name)?;
+        mknod(
+            &path,
+            SFlag::from_bits_truncate(mode & SFlag::S_IFMT.bits()),
+            Mode::from_bits_truncate(mode & 0o7777),
+            nix::sys::stat::makedev(major.into(), minor.into()),
+        )?;
+        reply.qid(&Qid::new(&metadata(&path)?));
+        Ok(())
+    }
+
+    fn readlink(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        let node = &self.fid(request.u32()?)?.node;
+        let target = readlinkat(Some(node.as_raw_fd()), "")?;
+        reply.string(target.as_bytes());
+        Ok(())
+    }
+
+    fn link(&mut self, request: &mut Reader) -> Result<(), Errno> {
+        let dfid = request.u32()?;
+        let fid = request.u32()?;
+        let name = request.string()?;
+        self.writable()?;
+        linkat(
+            None,
+            &proc_path(&self.fid(fid)?.node),
+            None,
+            &self.child(dfid, name)?,
+            AtFlags::AT_SYMLINK_FOLLOW,
+        )?;
+        Ok(())
+    }
+
+    fn rename(&mut self, request: &mut Reader) -> Result<(), Errno> {
+        let fid = request.u32()?;
+        let dfid = request.u32()?;
+        let name = request.string()?;
+        self.writable()?;
+        let new = self.child(dfid, name)?;
+        let dir = self.fid(dfid)?.node.clone();
+        let fid = self.fid_mut(fid)?;
+        let Some((old_dir, old_name)) = &fid.parent else {
+            return Err(EINVAL);
+        };
+        fs::rename(proc_path(old_dir).join(old_name), new)?;
+        fid.parent = Some((dir, name.to_string()));
+        Ok(())
+    }
+
+    fn renameat(&mut self, request: &mut Reader) -> Result<(), Errno> {
+        let old = self.child(request.u32()?, request.string()?)?;
+        let new = self.child(request.u32()?, request.string()?)?;
+        self.writable()?;
+        fs::rename(old, new)?;
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, request: &mut Reader) -> Result<(), Errno> {
+        let path = self.child(request.u32()?, request.string()?)?;
+        let flags = request.u32()?;
+        self.writable()?;
+        if flags & AT_REMOVEDIR != 0 {
+            fs::remove_dir(path)?;
+        } else if metadata(&path)?.is_dir() {
+            return Err(EISDIR);
+        } else {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn getlock(&mut self, request: &mut Reader, reply: &mut Writer) -> Result<(), Errno> {
+        self.fid(request.u32()?)?;
+        let _kind = request.u8()?;
+        let start = request.u64()?;
+        let length = request.u64()?;
+        let proc_id = request.u32()?;
+        let client_id = request.string()?;
+        reply.u8(LOCK_TYPE_UNLCK);
+        reply.u64(start);
+        reply.u64(length);
+        reply.u32(proc_id);
+        reply.string(client_id.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const ENOENT: Errno = Errno(2);
+    const ENOTDIR: Errno = Errno(20);
+    const ROOT: u32 = 0;
+
+    /// a share in a fresh temporary directory, next to a directory it must
+    /// not reach
+    struct Fixture {
+        dir: TempDir,
+        server: Server,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = TempDir::new(&format!("p9-{name}"));
+            fs::create_dir_all(dir.join("share")).unwrap();
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            fs::write(dir.join("outside/secret"), "secret").unwrap();
+
+            let mut fixture = Self {
+                server: Server::new(&dir.join("share"), false).unwrap(),
+                dir,
+            };
+            let mut attach = Writer::default();
+            attach.u32(ROOT);
+            fixture.call(TATTACH, attach).unwrap();
+            fixture
+        }
+
+        fn share(&self) -> PathBuf {
+            self.dir.join("share")
+        }
+
+        /// send a request, returns the body of the reply or the Rlerror errno
+        fn call(&mut self, kind: u8, body: Writer) -> Result<Vec<u8>, Errno> {
+            let mut request = ((HEADER_LEN + body.data.len()) as u32)
+                .to_le_bytes()
+                .to_vec();
+            request.push(kind);
+            request.extend_from_slice(&1u16.to_le_bytes());
+            request.extend_from_slice(&body.data);
+
+            let reply = self.server.handle(&request, 8192);
+            let mut reader = Reader::new(&reply);
+            assert_eq!(reader.u32().unwrap() as usize, reply.len());
+            let reply_kind = reader.u8().unwrap();
+            assert_eq!(reader.u16().unwrap(), 1);
+            let body = reply[HEADER_LEN..].to_vec();
+            if reply_kind == TLERROR + 1 {
+                return Err(Errno(Reader::new(&body).u32().unwrap()));
+            }
+            assert_eq!(reply_kind, kind + 1);
+            Ok(body)
+        }
+
+        /// walk `names` from `fid` to `newfid`, returns how many qids the
+        /// reply had
+        fn walk(&mut self, fid: u32, newfid: u32, names: &[&str]) -> Result<u16, Errno> {
+            let mut walk = Writer::default();
+            walk.u32(fid);
+            walk.u32(newfid);
+            walk.u16(names.len() as u16);
+            for name in names {
+                walk.string(name.as_bytes());
+            }
+            let reply = self.call(TWALK, walk)?;
+            Ok(Reader::new(&reply).u16().unwrap())
+        }
+
+        fn set_attr(&mut self, fid: u32, valid: u32, mode: u32, size: u64) -> Result<(), Errno> {
+            let mut setattr = Writer::default();
+            setattr.u32(fid);
+            setattr.u32(valid);
+            setattr.u32(mode);
+            // uid and gid
+            setattr.u32(0);
+            setattr.u32(0);
+            setattr.u64(size);
+            // atime and mtime
+            for _ in 0..4 {
+                setattr.u64(0);
+            }
+            self.call(TSETATTR, setattr).map(drop)
+        }
+
+        /// the inode number in the qid of `fid`
+        fn ino(&mut self, fid: u32) -> Result<u64, Errno> {
+            let mut getattr = Writer::default();
+            getattr.u32(fid);
+            let reply = self.call(TGETATTR, getattr)?;
+            let mut reader = Reader::new(&reply);
+            // valid, then the qid's type and version
+            reader.bytes(8 + 5)?;
+            reader.u64()
+        }
+    }
+
+    #[test]
+    fn dotdot_stops_at_the_root() {
+        let mut fixture = Fixture::new("dotdot");
+        let root = fixture.share().metadata().unwrap().ino();
+        assert_eq!(fixture.walk(ROOT, 1, &["..", ".."]), Ok(2));
+        assert_eq!(fixture.ino(1), Ok(root));
+
+        fs::create_dir(fixture.share().join("dir")).unwrap();
+        assert_eq!(fixture.walk(ROOT, 2, &["dir", "..", ".."]), Ok(3));
+        assert_eq!(fixture.ino(2), Ok(root));
+
+        // a partial walk, the sibling of the share is not found
+        assert_eq!(fixture.walk(ROOT, 3, &["..", "outside"]), Ok(1));
+        assert_eq!(fixture.ino(3), Err(EBADF));
+    }
+
+    #[test]
+    fn walks_stop_at_symlinks() {
+        let mut fixture = Fixture::new("symlink");
+        fs::create_dir(fixture.share().join("dir")).unwrap();
+        symlink("../../outside", fixture.share().join("dir/link")).unwrap();
+
+        assert_eq!(fixture.walk(ROOT, 1, &["dir", "link", "secret"]), Ok(1));
+        assert_eq!(fixture.ino(1), Err(EBADF));
+
+        // the symlink itself, but nothing behind it
+        assert_eq!(fixture.walk(ROOT, 2, &["dir", "link"]), Ok(2));
+        let mut readlink = Writer::default();
+        readlink.u32(2);
+        let target = fixture.call(TREADLINK, readlink).unwrap();
+        assert_eq!(Reader::new(&target).string(), Ok("../../outside"));
+        assert_eq!(fixture.walk(2, 3, &["secret"]), Err(ENOTDIR));
+        assert_eq!(fixture.walk(2, 3, &[".."]).map(drop), Err(ENOTDIR));
+    }
+
+    #[test]
+    fn setattr_does_not_follow_symlinks() {
+        let mut fixture = Fixture::new("setattr");
+        symlink("../outside/secret", fixture.share().join("link")).unwrap();
+        assert_eq!(fixture.walk(ROOT, 1, &["link"]), Ok(1));
+
+        assert_eq!(fixture.set_attr(1, SETATTR_MODE, 0o777, 0), Err(ELOOP));
+        assert_eq!(fixture.set_attr(1, SETATTR_SIZE, 0, 0), Err(ELOOP));
+        let secret = fixture.dir.join("outside/secret");
+        assert_eq!(fs::read(&secret).unwrap(), b"secret");
+        assert_ne!(secret.metadata().unwrap().mode() & 0o777, 0o777);
+    }
+
+    #[test]
+    fn fids_follow_renamed_directories() {
+        let mut fixture = Fixture::new("rename");
+        fs::create_dir(fixture.share().join("dir")).unwrap();
+        assert_eq!(fixture.walk(ROOT, 1, &["dir"]), Ok(1));
+
+        // swap the directory for a symlink out of the share
+        fs::rename(fixture.share().join("dir"), fixture.share().join("moved")).unwrap();
+        symlink("../outside", fixture.share().join("dir")).unwrap();
+
+        let mut lcreate = Writer::default();
+        lcreate.u32(1);
+        lcreate.string(b"file");
+        lcreate.u32(L_O_RDWR);
+        lcreate.u32(0o644);
+        lcreate.u32(0);
+        fixture.call(TLCREATE, lcreate).unwrap();
+        assert!(fixture.share().join("moved/file").exists());
+        assert!(!fixture.dir.join("outside/file").exists());
+    }
+
+    #[test]
+    fn names_stay_in_the_directory() {
+        let mut fixture = Fixture::new("names");
+        assert_eq!(fixture.walk(ROOT, 1, &["missing"]), Err(ENOENT));
+        assert_eq!(fixture.walk(ROOT, 1, &["../outside"]), Err(EINVAL));
+
+        let mut mkdir = Writer::default();
+        mkdir.u32(ROOT);
+        mkdir.string(b"..");
+        mkdir.u32(0o755);
+        mkdir.u32(0);
+        assert_eq!(fixture.call(TMKDIR, mkdir).map(drop), Err(EINVAL));
+    }
+}
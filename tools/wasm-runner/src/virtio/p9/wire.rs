@@ -0,0 +1,182 @@
+//! the 9p wire format, little endian integers and u16 length prefixed strings
+
+use super::server::{Errno, EINVAL};
+use std::os::unix::fs::MetadataExt;
+
+pub(super) const QID_LEN: usize = 13;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+/// parses the body of a request, running out of bytes is EINVAL
+pub(super) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Errno> {
+        let Some((bytes, rest)) = self.data.split_at_checked(len) else {
+            return Err(EINVAL);
+        };
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Errno> {
+        self.bytes(N)?.try_into().map_err(|_| EINVAL)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Errno> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Errno> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Errno> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Errno> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn string(&mut self) -> Result<&'a str, Errno> {
+        let len = usize::from(self.u16()?);
+        std::str::from_utf8(self.bytes(len)?).map_err(|_| EINVAL)
+    }
+}
+
+/// builds the body of a reply
+#[derive(Default)]
+pub(super) struct Writer {
+    pub data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn string(&mut self, string: &[u8]) {
+        self.u16(string.len() as u16);
+        self.bytes(string);
+    }
+
+    pub fn qid(&mut self, qid: &Qid) {
+        self.bytes(&qid.0);
+    }
+}
+
+/// what the server calls a file, its inode number and kind
+pub(super) struct Qid([u8; QID_LEN]);
+
+impl Qid {
+    pub fn new(metadata: &std::fs::Metadata) -> Self {
+        let kind = if metadata.is_dir() {
+            QTDIR
+        } else if metadata.is_symlink() {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+        let mut qid = [0; QID_LEN];
+        qid[0] = kind;
+        // the version tells clients whether cached data is stale
+        qid[1..5].copy_from_slice(
+            &(metadata.mtime_nsec() as u32 ^ metadata.size() as u32).to_le_bytes(),
+        );
+        qid[5..13].copy_from_slice(&metadata.ino().to_le_bytes());
+        Self(qid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = Writer::default();
+        writer.u8(0x12);
+        writer.u16(0x3456);
+        writer.u32(0x789a_bcde);
+        writer.u64(0x0123_4567_89ab_cdef);
+        writer.string("dir/ñame".as_bytes());
+        writer.string(b"");
+        writer.bytes(b"tail");
+        assert_eq!(&writer.data[..3], [0x12, 0x56, 0x34]);
+
+        let mut reader = Reader::new(&writer.data);
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u32(), Ok(0x789a_bcde));
+        assert_eq!(reader.u64(), Ok(0x0123_4567_89ab_cdef));
+        assert_eq!(reader.string(), Ok("dir/ñame"));
+        assert_eq!(reader.string(), Ok(""));
+        assert_eq!(reader.bytes(4), Ok(&b"tail"[..]));
+        assert_eq!(reader.u8(), Err(EINVAL));
+    }
+
+    #[test]
+    fn short_bodies_are_einval() {
+        assert_eq!(Reader::new(&[1]).u16(), Err(EINVAL));
+        assert_eq!(Reader::new(&[1, 2, 3]).u32(), Err(EINVAL));
+        assert_eq!(Reader::new(&[0; 7]).u64(), Err(EINVAL));
+        assert_eq!(Reader::new(&[]).bytes(1), Err(EINVAL));
+
+        // a string longer than what is left
+        assert_eq!(Reader::new(&[5, 0, b'a', b'b']).string(), Err(EINVAL));
+        assert_eq!(Reader::new(&[5]).string(), Err(EINVAL));
+
+        // a failed read takes nothing
+        let mut reader = Reader::new(&[1, 2, 3]);
+        assert_eq!(reader.u32(), Err(EINVAL));
+        assert_eq!(reader.bytes(3), Ok(&[1, 2, 3][..]));
+    }
+
+    #[test]
+    fn names_must_be_utf8() {
+        assert_eq!(Reader::new(&[2, 0, 0xc3, 0x28]).string(), Err(EINVAL));
+        assert_eq!(Reader::new(&[1, 0, 0xff]).string(), Err(EINVAL));
+    }
+
+    #[test]
+    fn qids() {
+        let dir = TempDir::new("p9-qids");
+        std::fs::write(dir.join("file"), "data").unwrap();
+        std::os::unix::fs::symlink("file", dir.join("link")).unwrap();
+
+        for (name, kind) in [("", QTDIR), ("file", QTFILE), ("link", QTSYMLINK)] {
+            let metadata = std::fs::symlink_metadata(dir.join(name)).unwrap();
+            let mut writer = Writer::default();
+            writer.qid(&Qid::new(&metadata));
+            assert_eq!(writer.data.len(), QID_LEN);
+            assert_eq!(writer.data[0], kind, "{name}");
+            assert_eq!(writer.data[5..], metadata.ino().to_le_bytes());
+        }
+    }
+}
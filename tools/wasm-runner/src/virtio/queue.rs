@@ -0,0 +1,189 @@
+use crate::memory::GuestMemory;
+use anyhow::{bail, Result};
+use std::sync::atomic::{fence, Ordering};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+
+/// a buffer in guest memory
+#[derive(Clone, Copy, Debug)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    /// the device writes to this buffer, otherwise it reads from it
+    pub writable: bool,
+}
+
+/// the buffers of one request, with indirect tables already resolved
+#[derive(Debug)]
+pub struct DescriptorChain {
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    fn read_descriptor(
+        memory: &GuestMemory,
+        table: u64,
+        index: u16,
+    ) -> Result<(Descriptor, u16, u16)> {
+        let addr = table + u64::from(index) * DESCRIPTOR_SIZE;
+        let flags = memory.read_u16(addr + 12)?;
+        let descriptor = Descriptor {
+            addr: memory.read_u64(addr)?,
+            len: memory.read_u32(addr + 8)?,
+            writable: flags & VIRTQ_DESC_F_WRITE != 0,
+        };
+        Ok((descriptor, flags, memory.read_u16(addr + 14)?))
+    }
+
+    /// follow the chain starting at `head` in a table of `size` descriptors
+    fn read_table(
+        memory: &GuestMemory,
+        table: u64,
+        size: u16,
+        head: u16,
+        indirect: bool,
+    ) -> Result<Vec<Descriptor>> {
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        // a chain can not be longer than its table, anything else is a loop
+        for _ in 0..size {
+            if index >= size {
+                bail!("descriptor {index} is out of range");
+            }
+            let (descriptor, flags, next) = Self::read_descriptor(memory, table, index)?;
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                if indirect {
+                    bail!("nested indirect descriptor table");
+                }
+                let size = u16::try_from(u64::from(descriptor.len) / DESCRIPTOR_SIZE)?;
+                descriptors.extend(Self::read_table(memory, descriptor.addr, size, 0, true)?);
+            } else {
+                descriptors.push(descriptor);
+            }
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(descriptors);
+            }
+            index = next;
+        }
+        bail!("descriptor chain loops")
+    }
+
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors
+            .iter()
+            .filter(|descriptor| !descriptor.writable)
+    }
+
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors
+            .iter()
+            .filter(|descriptor| descriptor.writable)
+    }
+
+    /// all device readable buffers, concatenated
+    pub fn read_all(&self, memory: &GuestMemory) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for descriptor in self.readable() {
+            let start = bytes.len();
+            bytes.resize(start + descriptor.len as usize, 0);
+            memory.read(descriptor.addr, &mut bytes[start..])?;
+        }
+        Ok(bytes)
+    }
+
+    /// fill the device writable buffers with `bytes`, returns how many bytes fit
+    pub fn write_all(&self, memory: &GuestMemory, mut bytes: &[u8]) -> Result<u32> {
+        let mut written = 0;
+        for descriptor in self.writable() {
+            if bytes.is_empty() {
+                break;
+            }
+            let len = bytes.len().min(descriptor.len as usize);
+            memory.write(descriptor.addr, &bytes[..len])?;
+            bytes = &bytes[len..];
+            written += len as u32;
+        }
+        Ok(written)
+    }
+
+    /// total size of the device writable buffers
+    pub fn writable_len(&self) -> u64 {
+        self.writable()
+            .map(|descriptor| u64::from(descriptor.len))
+            .sum()
+    }
+}
+
+/// a split virtqueue as configured by the driver
+#[derive(Clone, Debug)]
+pub struct Queue {
+    pub(crate) max_size: u16,
+    pub(crate) size: u16,
+    pub(crate) ready: bool,
+    pub(crate) desc_table: u64,
+    pub(crate) avail_ring: u64,
+    pub(crate) used_ring: u64,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl Queue {
+    pub(crate) fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            next_avail: 0,
+            next_used: 0,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// take the next request the driver made available
+    pub fn pop(&mut self, memory: &GuestMemory) -> Result<Option<DescriptorChain>> {
+        if !self.ready {
+            return Ok(None);
+        }
+
+        let avail_idx = memory.read_u16(self.avail_ring + 2)?;
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+        // the ring entries are only valid after reading the index
+        fence(Ordering::Acquire);
+
+        let slot = u64::from(self.next_avail % self.size);
+        let head = memory.read_u16(self.avail_ring + 4 + slot * 2)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        let descriptors =
+            DescriptorChain::read_table(memory, self.desc_table, self.size, head, false)?;
+        Ok(Some(DescriptorChain { head, descriptors }))
+    }
+
+    /// hand a request back to the driver, `len` bytes were written to it
+    pub fn add_used(&mut self, memory: &GuestMemory, head: u16, len: u32) -> Result<()> {
+        let slot = u64::from(self.next_used % self.size);
+        let elem = self.used_ring + 4 + slot * 8;
+        memory.write_u32(elem, u32::from(head))?;
+        memory.write_u32(elem + 4, len)?;
+
+        // the driver may only see the new index after the element
+        fence(Ordering::Release);
+        self.next_used = self.next_used.wrapping_add(1);
+        memory.write_u16(self.used_ring + 2, self.next_used)
+    }
+}
@@ -0,0 +1,109 @@
+use super::{Interrupt, Queue, VirtioDevice};
+use crate::memory::GuestMemory;
+use anyhow::{bail, Result};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+const VIRTIO_ID_ENTROPY: u32 = 4;
+
+const QUEUE_SIZE: u16 = 64;
+/// the most the guest gets per buffer, it asks again if it needs more
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+struct Active {
+    memory: GuestMemory,
+    interrupt: Interrupt,
+    queue: Queue,
+}
+
+/// virtio-rng, fills every buffer the guest offers with random bytes
+pub struct Entropy {
+    seed: Option<u64>,
+    rng: Box<dyn RngCore + Send>,
+    active: Option<Active>,
+}
+
+impl Entropy {
+    /// bytes from the host's entropy source
+    pub fn new() -> Self {
+        Self {
+            seed: None,
+            rng: Box::new(rand::rngs::OsRng),
+            active: None,
+        }
+    }
+
+    /// the same bytes on every boot, for reproducible runs
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seed: Some(seed),
+            rng: Box::new(StdRng::seed_from_u64(seed)),
+            active: None,
+        }
+    }
+
+    fn process_requests(&mut self) -> Result<()> {
+        let Some(active) = &mut self.active else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while let Some(request) = active.queue.pop(&active.memory)? {
+            let mut bytes = vec![0; request.writable_len().min(MAX_REQUEST_LEN) as usize];
+            self.rng.try_fill_bytes(&mut bytes)?;
+            let len = request.write_all(&active.memory, &bytes)?;
+            active.queue.add_used(&active.memory, request.head, len)?;
+            used = true;
+        }
+
+        if used {
+            active.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Entropy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtioDevice for Entropy {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_ENTROPY
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        _features: u64,
+    ) -> Result<()> {
+        let Some(queue) = queues.pop() else {
+            bail!("virtio-rng needs a request queue");
+        };
+        self.active = Some(Active {
+            memory,
+            interrupt,
+            queue,
+        });
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, _queue: usize) -> Result<()> {
+        self.process_requests()
+    }
+
+    fn reset(&mut self) {
+        self.active = None;
+        // a reboot starts the sequence over
+        if let Some(seed) = self.seed {
+            self.rng = Box::new(StdRng::seed_from_u64(seed));
+        }
+    }
+}
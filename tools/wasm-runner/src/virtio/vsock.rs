@@ -0,0 +1,690 @@
+//! virtio-vsock bridged to host unix sockets, the way firecracker does it
+//!
+//! a guest connection to port `p` on the host connects to `<uds_path>_<p>`.
+//! host programs connect to `<uds_path>`, write `CONNECT <port>\n` and get
+//! `OK <host port>\n` once the guest accepted, everything after that is the
+//! stream.
+
+use super::{Interrupt, Queue, VirtioDevice};
+use crate::memory::GuestMemory;
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Condvar, Mutex,
+    },
+};
+
+const VIRTIO_ID_VSOCK: u32 = 19;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const EVENT_QUEUE: usize = 2;
+const QUEUE_SIZE: u16 = 256;
+
+const HOST_CID: u64 = 2;
+const DEFAULT_GUEST_CID: u64 = 3;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+const HEADER_LEN: usize = 44;
+/// guest data the runner buffers per connection on its way to the host
+const BUF_ALLOC: u32 = 256 * 1024;
+/// the most host data in one packet
+const MAX_PAYLOAD: usize = 4096;
+/// the longest `CONNECT <port>\n` line
+const MAX_CONNECT_LINE: usize = 32;
+/// host ports for connections from host programs, as the guest sees them
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+/// the value of a `--vsock` option, `<uds_path>[,cid=<cid>]`
+#[derive(Clone, Debug)]
+pub struct VsockOptions {
+    pub uds_path: PathBuf,
+    pub guest_cid: u64,
+}
+
+impl FromStr for VsockOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(options: &str) -> Result<Self> {
+        let mut options = options.split(',');
+        let uds_path = PathBuf::from(options.next().unwrap_or_default());
+        let mut guest_cid = DEFAULT_GUEST_CID;
+        for option in options {
+            match option.split_once('=') {
+                Some(("cid", cid)) => guest_cid = cid.parse().context("invalid cid")?,
+                _ => bail!("unknown vsock option {option}"),
+            }
+        }
+        if uds_path.as_os_str().is_empty() {
+            bail!("a vsock device needs a unix socket path");
+        }
+        if guest_cid <= HOST_CID {
+            bail!("cid {guest_cid} is reserved");
+        }
+        Ok(Self {
+            uds_path,
+            guest_cid,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    kind: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let u32_at = |at: usize| Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?));
+        let u16_at = |at: usize| Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?));
+        let u64_at = |at: usize| Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?));
+        Some(Self {
+            src_cid: u64_at(0)?,
+            dst_cid: u64_at(8)?,
+            src_port: u32_at(16)?,
+            dst_port: u32_at(20)?,
+            len: u32_at(24)?,
+            kind: u16_at(28)?,
+            op: u16_at(30)?,
+            flags: u32_at(32)?,
+            buf_alloc: u32_at(36)?,
+            fwd_cnt: u32_at(40)?,
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(&self.src_cid.to_le_bytes());
+        bytes.extend_from_slice(&self.dst_cid.to_le_bytes());
+        bytes.extend_from_slice(&self.src_port.to_le_bytes());
+        bytes.extend_from_slice(&self.dst_port.to_le_bytes());
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        bytes.extend_from_slice(&self.kind.to_le_bytes());
+        bytes.extend_from_slice(&self.op.to_le_bytes());
+        bytes.extend_from_slice(&self.flags.to_le_bytes());
+        bytes.extend_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes.extend_from_slice(&self.fwd_cnt.to_le_bytes());
+        bytes
+    }
+}
+
+struct Packet {
+    header: Header,
+    data: Vec<u8>,
+}
+
+/// (guest port, host port)
+type Key = (u32, u32);
+
+struct Connection {
+    /// tells the threads of a connection from the one that replaced it
+    id: u64,
+    stream: UnixStream,
+    /// the guest has not answered a host program's request yet
+    connecting: bool,
+    /// chunks for the host writer thread, `None` shuts down its side
+    to_host: Option<Sender<Option<Vec<u8>>>>,
+    /// bytes sent to the guest
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// bytes of guest data written to the host
+    fwd_cnt: u32,
+    /// `fwd_cnt` as the guest last heard it
+    fwd_cnt_sent: u32,
+}
+
+impl Connection {
+    fn new(id: u64, stream: UnixStream, connecting: bool) -> Self {
+        Self {
+            id,
+            stream,
+            connecting,
+            to_host: None,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            fwd_cnt: 0,
+            fwd_cnt_sent: 0,
+        }
+    }
+
+    /// how much the guest can take
+    fn credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    fn close(&mut self) {
+        self.to_host = None;
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+struct Active {
+    memory: GuestMemory,
+    interrupt: Interrupt,
+    queue: Queue,
+}
+
+struct Inner {
+    guest_cid: u64,
+    rx: Option<Active>,
+    pending: VecDeque<Packet>,
+    connections: HashMap<Key, Connection>,
+    next_host_port: u32,
+    next_id: u64,
+}
+
+impl Inner {
+    fn push(&mut self, (guest_port, host_port): Key, op: u16, flags: u32, data: Vec<u8>) {
+        let fwd_cnt = self
+            .connections
+            .get_mut(&(guest_port, host_port))
+            .map_or(0, |connection| {
+                connection.fwd_cnt_sent = connection.fwd_cnt;
+                connection.fwd_cnt
+            });
+        let header = Header {
+            src_cid: HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: host_port,
+            dst_port: guest_port,
+            len: data.len() as u32,
+            kind: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt,
+        };
+        self.pending.push_back(Packet { header, data });
+    }
+
+    /// add a connection on `key`, closing the one it replaces
+    fn open(&mut self, key: Key, stream: UnixStream, connecting: bool) -> &mut Connection {
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(mut old) = self.connections.remove(&key) {
+            old.close();
+        }
+        self.connections
+            .entry(key)
+            .or_insert(Connection::new(id, stream, connecting))
+    }
+
+    /// the connection on `key` if it is still the one with `id`
+    fn connection(&mut self, key: Key, id: u64) -> Option<&mut Connection> {
+        self.connections
+            .get_mut(&key)
+            .filter(|connection| connection.id == id)
+    }
+
+    fn reset(&mut self, key: Key) {
+        if let Some(mut connection) = self.connections.remove(&key) {
+            connection.close();
+        }
+        self.push(key, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+    }
+
+    /// move pending packets into the buffers the driver made available
+    fn deliver(&mut self) -> Result<()> {
+        let Some(active) = &mut self.rx else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while let Some(mut packet) = self.pending.pop_front() {
+            let Some(buffer) = active.queue.pop(&active.memory)? else {
+                self.pending.push_front(packet);
+                break;
+            };
+            // data that does not fit goes into the next buffer
+            let room = (buffer.writable_len() as usize).saturating_sub(HEADER_LEN);
+            if packet.data.len() > room {
+                let rest = packet.data.split_off(room);
+                let mut header = packet.header;
+                header.len = rest.len() as u32;
+                self.pending.push_front(Packet { header, data: rest });
+                packet.header.len = room as u32;
+            }
+
+            let mut bytes = packet.header.to_bytes();
+            bytes.extend_from_slice(&packet.data);
+            let len = buffer.write_all(&active.memory, &bytes)?;
+            active.queue.add_used(&active.memory, buffer.head, len)?;
+            used = true;
+        }
+
+        if used {
+            active.interrupt.signal_used()?;
+        }
+        Ok(())
+    }
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    /// the guest sent credit, or a connection went away
+    changed: Condvar,
+}
+
+impl Shared {
+    fn deliver(&self, inner: &mut Inner) {
+        if let Err(err) = inner.deliver() {
+            eprintln!("virtio-vsock: {err:#}");
+        }
+    }
+
+    /// start moving data between the guest and an established connection
+    fn start(self: &Arc<Self>, inner: &mut Inner, key: Key) -> Result<()> {
+        let Some(connection) = inner.connections.get_mut(&key) else {
+            return Ok(());
+        };
+        let id = connection.id;
+        let reader = connection.stream.try_clone()?;
+        let mut writer = connection.stream.try_clone()?;
+        let (to_host, chunks) = mpsc::channel::<Option<Vec<u8>>>();
+        connection.to_host = Some(to_host);
+
+        let shared = self.clone();
+        std::thread::Builder::new()
+            .name(String::from("virtio-vsock"))
+            .spawn(move || shared.read_host(key, id, reader))?;
+
+        let shared = self.clone();
+        std::thread::Builder::new()
+            .name(String::from("virtio-vsock"))
+            .spawn(move || {
+                for chunk in chunks {
+                    let Some(chunk) = chunk else {
+                        let _ = writer.shutdown(Shutdown::Write);
+                        continue;
+                    };
+                    let result = writer.write_all(&chunk);
+                    let mut inner = shared.inner.lock().unwrap();
+                    if inner.connection(key, id).is_none() {
+                        return;
+                    }
+                    if result.is_err() {
+                        inner.reset(key);
+                        shared.deliver(&mut inner);
+                        return;
+                    }
+                    let Some(connection) = inner.connection(key, id) else {
+                        return;
+                    };
+                    connection.fwd_cnt = connection.fwd_cnt.wrapping_add(chunk.len() as u32);
+                    // tell the guest about the room before it runs out
+                    if connection.fwd_cnt.wrapping_sub(connection.fwd_cnt_sent) >= BUF_ALLOC / 4 {
+                        inner.push(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+                        shared.deliver(&mut inner);
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    fn read_host(&self, key: Key, id: u64, mut reader: UnixStream) {
+        let mut buf = vec![0; MAX_PAYLOAD];
+        loop {
+            // only read what the guest has room for
+            let mut inner = self.inner.lock().unwrap();
+            let credit = loop {
+                let Some(connection) = inner.connection(key, id) else {
+                    return;
+                };
+                match connection.credit() {
+                    0 => inner = self.changed.wait(inner).unwrap(),
+                    credit => break credit as usize,
+                }
+            };
+            drop(inner);
+
+            let result = reader.read(&mut buf[..credit.min(MAX_PAYLOAD)]);
+            let mut inner = self.inner.lock().unwrap();
+            if inner.connection(key, id).is_none() {
+                return;
+            }
+            match result {
+                Ok(0) => {
+                    inner.push(
+                        key,
+                        VIRTIO_VSOCK_OP_SHUTDOWN,
+                        VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND,
+                        Vec::new(),
+                    );
+                    self.deliver(&mut inner);
+                    return;
+                }
+                Ok(len) => {
+                    if let Some(connection) = inner.connection(key, id) {
+                        connection.tx_cnt = connection.tx_cnt.wrapping_add(len as u32);
+                    }
+                    inner.push(key, VIRTIO_VSOCK_OP_RW, 0, buf[..len].to_vec());
+                    self.deliver(&mut inner);
+                }
+                Err(_) => {
+                    inner.reset(key);
+                    self.deliver(&mut inner);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// a host program connected to the device's socket
+    fn accept(self: &Arc<Self>, mut stream: UnixStream) -> Result<()> {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while line.len() < MAX_CONNECT_LINE {
+            stream.read_exact(&mut byte)?;
+            if byte[0] == b'\n' {
+                break;
+            }
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line);
+        let Some(port) = line
+            .strip_prefix("CONNECT ")
+            .and_then(|port| port.trim().parse().ok())
+        else {
+            bail!("expected CONNECT <port>, got {line}");
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rx.is_none() {
+            bail!("the guest driver is not ready");
+        }
+        // skip host ports of connections that are still open
+        let key = loop {
+            let key = (port, inner.next_host_port);
+            inner.next_host_port = inner.next_host_port.wrapping_add(1).max(FIRST_HOST_PORT);
+            if !inner.connections.contains_key(&key) {
+                break key;
+            }
+        };
+        inner.open(key, stream, true);
+        inner.push(key, VIRTIO_VSOCK_OP_REQUEST, 0, Vec::new());
+        self.deliver(&mut inner);
+        Ok(())
+    }
+
+    /// a packet from the guest
+    fn transmit(self: &Arc<Self>, uds_path: &Path, header: &Header, data: &[u8]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if header.dst_cid != HOST_CID || header.kind != VIRTIO_VSOCK_TYPE_STREAM {
+            let key = (header.src_port, header.dst_port);
+            inner.push(key, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+            return Ok(());
+        }
+        let key = (header.src_port, header.dst_port);
+
+        if let Some(connection) = inner.connections.get_mut(&key) {
+            connection.peer_buf_alloc = header.buf_alloc;
+            connection.peer_fwd_cnt = header.fwd_cnt;
+        }
+
+        match header.op {
+            VIRTIO_VSOCK_OP_REQUEST => {
+                let path = format!("{}_{}", uds_path.display(), header.dst_port);
+                match UnixStream::connect(&path) {
+                    Ok(stream) => {
+                        // a request on a live key means the guest forgot
+                        // the old connection, it goes away without a reset
+                        let connection = inner.open(key, stream, false);
+                        connection.peer_buf_alloc = header.buf_alloc;
+                        connection.peer_fwd_cnt = header.fwd_cnt;
+                        inner.push(key, VIRTIO_VSOCK_OP_RESPONSE, 0, Vec::new());
+                        self.start(&mut inner, key)?;
+                    }
+                    Err(_) => inner.push(key, VIRTIO_VSOCK_OP_RST, 0, Vec::new()),
+                }
+            }
+            VIRTIO_VSOCK_OP_RESPONSE => {
+                let Some(connection) = inner.connections.get_mut(&key) else {
+                    inner.push(key, VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+                    return Ok(());
+                };
+                if !connection.connecting {
+                    inner.reset(key);
+                    return Ok(());
+                }
+                connection.connecting = false;
+                if writeln!(connection.stream, "OK {}", key.1).is_err() {
+                    inner.reset(key);
+                    return Ok(());
+                }
+                self.start(&mut inner, key)?;
+            }
+            VIRTIO_VSOCK_OP_RW => {
+                let Some(to_host) = inner
+                    .connections
+                    .get(&key)
+                    .and_then(|connection| connection.to_host.as_ref())
+                else {
+                    inner.reset(key);
+                    return Ok(());
+                };
+                let _ = to_host.send(Some(data.to_vec()));
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                let both = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+                if header.flags & both == both {
+                    // the guest is done, the reset completes the close
+                    inner.reset(key);
+                } else if header.flags & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+                    if let Some(to_host) = inner
+                        .connections
+                        .get(&key)
+                        .and_then(|connection| connection.to_host.as_ref())
+                    {
+                        let _ = to_host.send(None);
+                    }
+                }
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                if let Some(mut connection) = inner.connections.remove(&key) {
+                    connection.close();
+                }
+            }
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                if inner.connections.contains_key(&key) {
+                    inner.push(key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+                }
+            }
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => {}
+            _ => inner.reset(key),
+        }
+        // readers waiting for credit notice closed connections too
+        self.changed.notify_all();
+        Ok(())
+    }
+}
+
+/// virtio-vsock with the host side on unix sockets below `uds_path`
+pub struct Vsock {
+    uds_path: PathBuf,
+    shared: Arc<Shared>,
+    tx: Option<Active>,
+    event: Option<Queue>,
+}
+
+impl Vsock {
+    pub fn new(options: &VsockOptions) -> Result<Self> {
+        let uds_path = options.uds_path.clone();
+        // a socket left behind by an earlier run
+        if fs::symlink_metadata(&uds_path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(&uds_path)?;
+        }
+        let listener = UnixListener::bind(&uds_path)
+            .with_context(|| format!("while binding vsock to {}", uds_path.display()))?;
+
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                guest_cid: options.guest_cid,
+                rx: None,
+                pending: VecDeque::new(),
+                connections: HashMap::new(),
+                next_host_port: FIRST_HOST_PORT,
+                next_id: 0,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let accepting = shared.clone();
+        std::thread::Builder::new()
+            .name(String::from("virtio-vsock"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    // a client that never sends its CONNECT line only holds
+                    // up its own thread
+                    let shared = accepting.clone();
+                    let result = stream.map_err(anyhow::Error::from).and_then(|stream| {
+                        std::thread::Builder::new()
+                            .name(String::from("virtio-vsock"))
+                            .spawn(move || {
+                                if let Err(err) = shared.accept(stream) {
+                                    eprintln!(
+                                        "virtio-vsock: while accepting a host connection: {err:#}"
+                                    );
+                                }
+                            })?;
+                        Ok(())
+                    });
+                    if let Err(err) = result {
+                        eprintln!("virtio-vsock: while accepting a host connection: {err:#}");
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            uds_path,
+            shared,
+            tx: None,
+            event: None,
+        })
+    }
+
+    fn transmit(&mut self) -> Result<()> {
+        let Some(tx) = &mut self.tx else {
+            return Ok(());
+        };
+
+        let mut used = false;
+        while let Some(request) = tx.queue.pop(&tx.memory)? {
+            let packet = request.read_all(&tx.memory)?;
+            tx.queue.add_used(&tx.memory, request.head, 0)?;
+            used = true;
+
+            let Some(header) = Header::parse(&packet) else {
+                bail!("vsock packet without a header");
+            };
+            let data = &packet[HEADER_LEN..];
+            let data = &data[..data.len().min(header.len as usize)];
+            self.shared.transmit(&self.uds_path, &header, data)?;
+        }
+
+        if used {
+            tx.interrupt.signal_used()?;
+        }
+        let mut inner = self.shared.inner.lock().unwrap();
+        self.shared.deliver(&mut inner);
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; 3]
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let guest_cid = self.shared.inner.lock().unwrap().guest_cid;
+        guest_cid.to_le_bytes().to_vec()
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        _features: u64,
+    ) -> Result<()> {
+        let Ok([rx, tx, event]) = <[Queue; 3]>::try_from(queues) else {
+            bail!("virtio-vsock needs a receive, a transmit and an event queue");
+        };
+        self.tx = Some(Active {
+            memory: memory.clone(),
+            interrupt: interrupt.clone(),
+            queue: tx,
+        });
+        // the event queue is only for transport resets after migration
+        self.event = Some(event);
+        self.shared.inner.lock().unwrap().rx = Some(Active {
+            memory,
+            interrupt,
+            queue: rx,
+        });
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, queue: usize) -> Result<()> {
+        match queue {
+            RX_QUEUE => {
+                let mut inner = self.shared.inner.lock().unwrap();
+                inner.deliver()
+            }
+            TX_QUEUE => self.transmit(),
+            EVENT_QUEUE => Ok(()),
+            _ => bail!("virtio-vsock has no queue {queue}"),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.tx = None;
+        self.event = None;
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.rx = None;
+        inner.pending.clear();
+        for (_, mut connection) in inner.connections.drain() {
+            connection.close();
+        }
+        self.shared.changed.notify_all();
+    }
+}
@@ -0,0 +1,674 @@
+use crate::{
+    clock::{start_time_page, Clock, ClockMode, TIME_PAGE_LEN},
+    console::{self, Console, ConsoleBackend},
+    cpio,
+    devicetree::{create_devicetree, load_sections, Platform, Sections},
+    imports::add_imports,
+    irq::InterruptController,
+    lifecycle::{ExitReason, Lifecycle, Stopped, ThreadStatus},
+    memory::{GuestMemory, Layout},
+    timer::Timers,
+    virtio::{SharedDevice, VirtioDevice, VirtioMmio, WINDOW_SIZE},
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    any::Any,
+    fs::File,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use wasmtime::{
+    Config, Engine, InstancePre, Linker, MemoryType, Module, SharedMemory, Store, Trap,
+    WasmBacktraceDetails, WasmCoreDump,
+};
+
+const PAGES_PER_MIB: u32 = 16;
+const BYTES_PER_MIB: u32 = 0x100000;
+
+/// how long a reboot waits for the threads of the previous boot to exit
+const JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct State {
+    pub(crate) memory: SharedMemory,
+    /// the cpu this thread runs, none for kernel workers
+    pub(crate) cpu: Option<u32>,
+    pub(crate) devicetree: Vec<u8>,
+    pub(crate) clock: Arc<Clock>,
+    /// fuel left in this thread's store when the clock was last charged
+    pub(crate) fuel: u64,
+    pub(crate) instance_pre: Option<InstancePre<State>>,
+    pub(crate) lifecycle: Arc<Lifecycle>,
+    pub(crate) console: Arc<Console>,
+    pub(crate) virtio: Arc<VirtioMmio>,
+    pub(crate) interrupts: Arc<InterruptController>,
+    pub(crate) timers: Arc<Timers>,
+}
+
+enum ModuleSource {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+/// configures and boots a wasm kernel
+pub struct VmBuilder {
+    module: ModuleSource,
+    sections: Sections,
+    cmdline: String,
+    memory: u32,
+    cpus: u32,
+    debug: bool,
+    reboot: bool,
+    interactive: bool,
+    console: ConsoleBackend,
+    console_log: Option<(PathBuf, bool)>,
+    initrd: Option<Vec<u8>>,
+    devices: Vec<SharedDevice>,
+    rng_seed: Option<u64>,
+    rtc_start: Option<SystemTime>,
+    clock: ClockMode,
+}
+
+impl VmBuilder {
+    fn new(module: ModuleSource) -> Self {
+        Self {
+            module,
+            sections: Sections::new(),
+            cmdline: String::from("no_hash_pointers"),
+            memory: 128,
+            cpus: num_cpus::get() as u32,
+            debug: false,
+            reboot: true,
+            interactive: false,
+            console: ConsoleBackend::Stdio,
+            console_log: None,
+            initrd: None,
+            devices: Vec::new(),
+            rng_seed: None,
+            rtc_start: None,
+            clock: ClockMode::HostReal,
+        }
+    }
+
+    /// load the kernel from a wasm file
+    pub fn from_file(path: impl Into<PathBuf>) -> Self {
+        Self::new(ModuleSource::File(path.into()))
+    }
+
+    /// load the kernel from an in-memory wasm binary
+    pub fn from_bytes(bytes: impl Into<Vec<u8>>) -> Self {
+        Self::new(ModuleSource::Bytes(bytes.into()))
+    }
+
+    /// data sections of the kernel image
+    pub fn sections(mut self, sections: Sections) -> Self {
+        self.sections = sections;
+        self
+    }
+
+    /// read the data sections from a sections json file
+    pub fn sections_file(self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let sections = load_sections(path)
+            .with_context(|| format!("while reading sections from {}", path.display()))?;
+        Ok(self.sections(sections))
+    }
+
+    /// kernel command line
+    pub fn cmdline(mut self, cmdline: impl Into<String>) -> Self {
+        self.cmdline = cmdline.into();
+        self
+    }
+
+    /// amount of memory in MiB
+    pub fn memory(mut self, mib: u32) -> Self {
+        self.memory = mib;
+        self
+    }
+
+    /// number of cpus advertised to the kernel
+    pub fn cpus(mut self, cpus: u32) -> Self {
+        self.cpus = cpus;
+        self
+    }
+
+    /// enable debug info, unoptimized code and coredumps on trap
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// write all console output to a file as well, with each line prefixed
+    /// by the time since boot if `timestamps` is set
+    pub fn console_log(mut self, path: impl Into<PathBuf>, timestamps: bool) -> Self {
+        self.console_log = Some((path.into(), timestamps));
+        self
+    }
+
+    /// initial ramdisk, usually a cpio archive
+    pub fn initrd(mut self, initrd: impl Into<Vec<u8>>) -> Self {
+        self.initrd = Some(initrd.into());
+        self
+    }
+
+    /// read the initial ramdisk from a file
+    pub fn initrd_file(self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let initrd = std::fs::read(path)
+            .with_context(|| format!("while reading initrd from {}", path.display()))?;
+        Ok(self.initrd(initrd))
+    }
+
+    /// generate an initramfs from a host directory
+    ///
+    /// device nodes, which usually cannot be created on the host, and other
+    /// extra entries are read from a manifest in gen_init_cpio format.
+    pub fn initramfs_dir(self, dir: impl AsRef<Path>, manifest: Option<&Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let initramfs = cpio::from_dir(dir, manifest)
+            .with_context(|| format!("while building an initramfs from {}", dir.display()))?;
+        Ok(self.initrd(initramfs))
+    }
+
+    /// seed the devicetree's `rng-seed` instead of using host entropy, so
+    /// that runs are reproducible
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// start the guest's wall clock at `start` on every boot instead of the
+    /// host's time, so that runs are reproducible
+    pub fn rtc_start(mut self, start: SystemTime) -> Self {
+        self.rtc_start = Some(start);
+        self
+    }
+
+    /// how the guest's clocks and timers advance, a [`ClockMode::Virtual`]
+    /// clock needs a single cpu
+    pub fn clock(mut self, clock: ClockMode) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// add a device on a virtio-mmio transport
+    pub fn virtio_device(mut self, device: impl VirtioDevice + 'static) -> Self {
+        self.devices.push(Arc::new(Mutex::new(Box::new(device))));
+        self
+    }
+
+    /// reboot in-process when the guest restarts, instead of stopping with
+    /// [`ExitReason::Reboot`]
+    pub fn reboot(mut self, reboot: bool) -> Self {
+        self.reboot = reboot;
+        self
+    }
+
+    /// forward stdin to the guest console, with the host terminal in raw mode
+    ///
+    /// ctrl-a starts an escape sequence handled by the runner, ctrl-a h lists
+    /// the commands. only applies to [`ConsoleBackend::Stdio`], the other
+    /// backends always take input.
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
+    /// where to connect the guest console
+    pub fn console(mut self, console: ConsoleBackend) -> Self {
+        self.console = console;
+        self
+    }
+
+    /// compile the kernel and start the boot cpu
+    pub fn build(self) -> Result<Vm> {
+        // every cpu would advance the one clock by its own instructions
+        if matches!(self.clock, ClockMode::Virtual) && self.cpus > 1 {
+            bail!("a virtual clock needs a single cpu, not {}", self.cpus);
+        }
+
+        let mut config = Config::new();
+        config.epoch_interruption(true);
+        // a virtual clock counts the instructions the guest executed
+        config.consume_fuel(matches!(self.clock, ClockMode::Virtual));
+        if self.debug {
+            config.debug_info(true);
+            config.native_unwind_info(true);
+            config.wasm_backtrace_details(WasmBacktraceDetails::Enable);
+            config.coredump_on_trap(true);
+            config.cranelift_opt_level(wasmtime::OptLevel::None);
+        }
+        let engine = Engine::new(&config)?;
+
+        let module = match &self.module {
+            ModuleSource::File(path) => Module::from_file(&engine, path)?,
+            ModuleSource::Bytes(bytes) => Module::new(&engine, bytes)?,
+        };
+
+        let mut linker = Linker::new(&engine);
+        add_imports(&mut linker)?;
+        // every boot defines a fresh memory
+        linker.allow_shadowing(true);
+
+        let console = Arc::new(Console::new(&self.console));
+        if let Some((path, timestamps)) = &self.console_log {
+            let file =
+                File::create(path).with_context(|| format!("while creating {}", path.display()))?;
+            console.log_to(file, *timestamps);
+        }
+        let console_pty = console.start(&self.console, self.interactive)?;
+
+        let mut machine = Machine {
+            engine,
+            module,
+            linker,
+            builder: self,
+            console,
+        };
+        let boot = match machine.boot() {
+            Ok(boot) => boot,
+            Err(err) => {
+                console::restore_terminal();
+                return Err(err);
+            }
+        };
+
+        Ok(Vm {
+            machine,
+            console_pty,
+            boot,
+        })
+    }
+}
+
+/// everything that outlives a reboot
+struct Machine {
+    engine: Engine,
+    module: Module,
+    linker: Linker<State>,
+    builder: VmBuilder,
+    console: Arc<Console>,
+}
+
+impl Machine {
+    /// create a fresh memory and devicetree and start the boot cpu
+    fn boot(&mut self) -> Result<Boot> {
+        let Machine {
+            engine,
+            module,
+            linker,
+            builder,
+            console,
+        } = self;
+
+        let memory_pages = builder.memory * PAGES_PER_MIB;
+        let memory_bytes = builder.memory * BYTES_PER_MIB;
+
+        let memory = SharedMemory::new(engine, MemoryType::shared(memory_pages, memory_pages))?;
+        debug_assert_eq!(memory.data_size(), memory_bytes as usize);
+
+        let guest_memory = GuestMemory::new(memory.clone());
+        let mut layout = Layout::new(&builder.sections, memory_bytes);
+
+        // device memory is not part of the kernel's memory
+        let intc_len = InterruptController::region_len(builder.cpus);
+        let intc_base = layout
+            .place(intc_len)
+            .context("while placing the interrupt controller")?;
+        let interrupts = Arc::new(InterruptController::new(
+            guest_memory.clone(),
+            intc_base,
+            builder.cpus,
+        ));
+        let time_page = layout
+            .place(TIME_PAGE_LEN)
+            .context("while placing the time page")?;
+        let virtio_len = WINDOW_SIZE * builder.devices.len() as u32;
+        let virtio_base = layout
+            .place(virtio_len)
+            .context("while placing the virtio devices")?;
+        let lifecycle = Arc::new(Lifecycle::new(engine.clone()));
+        let stopped = interrupts.clone();
+        // idle cpus return from `kernel.wait_for_interrupt`
+        lifecycle.on_stop(move || {
+            if let Err(err) = stopped.stop() {
+                eprintln!("while waking cpus: {err:#}");
+            }
+        });
+        let virtio = VirtioMmio::new(
+            &guest_memory,
+            virtio_base,
+            &interrupts,
+            &lifecycle,
+            &builder.devices,
+        )?;
+        let ram_bytes = layout.top();
+
+        let initrd = match &builder.initrd {
+            Some(initrd) => {
+                let len = u32::try_from(initrd.len())?;
+                let start = layout.place(len).context("while placing the initrd")?;
+                guest_memory.write(u64::from(start), initrd)?;
+                Some((start, start + len))
+            }
+            None => None,
+        };
+
+        let clock = Arc::new(Clock::new(
+            builder.clock,
+            builder.rtc_start.unwrap_or_else(SystemTime::now),
+        ));
+        start_time_page(
+            guest_memory.clone(),
+            time_page,
+            clock.clone(),
+            lifecycle.clone(),
+        )?;
+        console.attach(lifecycle.clone(), Instant::now(), interrupts.clone());
+        let timers = Timers::start(
+            builder.cpus,
+            clock.clone(),
+            interrupts.clone(),
+            lifecycle.clone(),
+        )?;
+        let mut store = Store::new(
+            engine,
+            State {
+                memory: memory.clone(),
+                cpu: None,
+                devicetree: create_devicetree(&Platform {
+                    cmdline: &builder.cmdline,
+                    sections: &builder.sections,
+                    ram_bytes,
+                    ncpus: builder.cpus,
+                    initrd,
+                    interrupt_controller: (intc_base, intc_len),
+                    time_page,
+                    virtio_mmio: virtio.windows().collect(),
+                    rng_seed: builder.rng_seed,
+                })?,
+                clock: clock.clone(),
+                fuel: 0,
+                instance_pre: None,
+                lifecycle: lifecycle.clone(),
+                console: console.clone(),
+                virtio: Arc::new(virtio),
+                interrupts: interrupts.clone(),
+                timers,
+            },
+        );
+
+        linker.define(&store, "env", "memory", memory.clone())?;
+
+        // an `InstancePre` keeps the memory it was resolved against, so it
+        // cannot outlive a boot. reusing the memory instead would let a
+        // thread the previous boot left behind in a wait wake up in the new
+        // kernel. the compiled module is still shared.
+        let instance_pre = linker.instantiate_pre(module)?;
+        store.data_mut().instance_pre = Some(instance_pre.clone());
+
+        spawn(
+            engine,
+            store.into_data(),
+            String::from("boot"),
+            Some(0),
+            move |store| {
+                instance_pre
+                    .instantiate(&mut *store)?
+                    .get_typed_func::<(), ()>(&mut *store, "boot")?
+                    .call(&mut *store, ())?;
+                // the boot cpu never returns unless the kernel is done
+                store.data().lifecycle.stop(ExitReason::PowerOff);
+                Ok(())
+            },
+        )?;
+
+        Ok(Boot {
+            memory,
+            lifecycle,
+            interrupts,
+            clock,
+        })
+    }
+}
+
+/// what every boot creates anew
+struct Boot {
+    memory: SharedMemory,
+    lifecycle: Arc<Lifecycle>,
+    interrupts: Arc<InterruptController>,
+    clock: Arc<Clock>,
+}
+
+/// a running wasm kernel
+pub struct Vm {
+    machine: Machine,
+    console_pty: Option<PathBuf>,
+    boot: Boot,
+}
+
+impl Vm {
+    /// the guest's physical memory, replaced on every reboot
+    pub fn memory(&self) -> &SharedMemory {
+        &self.boot.memory
+    }
+
+    /// raises interrupts in the guest, replaced on every reboot
+    pub fn interrupts(&self) -> Arc<InterruptController> {
+        self.boot.interrupts.clone()
+    }
+
+    /// the guest's clocks, replaced on every reboot
+    pub fn clock(&self) -> Arc<Clock> {
+        self.boot.clock.clone()
+    }
+
+    /// the pseudo terminal of a [`ConsoleBackend::Pty`] console
+    pub fn console_pty(&self) -> Option<&Path> {
+        self.console_pty.as_deref()
+    }
+
+    /// stop every cpu and worker thread as if the guest powered off
+    pub fn power_off(&self) {
+        self.boot.lifecycle.stop(ExitReason::PowerOff);
+    }
+
+    /// block until the vm stops, rebooting it in between if enabled
+    pub fn wait(mut self) -> Result<ExitReason> {
+        let result = loop {
+            let reason = self.boot.lifecycle.wait();
+            if !matches!(reason, ExitReason::Reboot) || !self.machine.builder.reboot {
+                break Ok(reason);
+            }
+
+            // the old threads hold on to the old memory
+            let left = self.boot.lifecycle.join(JOIN_TIMEOUT);
+            if left > 0 {
+                eprintln!("{left} threads of the previous boot did not stop, leaving them behind");
+            }
+
+            match self.machine.boot() {
+                Ok(boot) => self.boot = boot,
+                Err(err) => break Err(err),
+            }
+        };
+        console::restore_terminal();
+        result
+    }
+}
+
+/// run `entry` on a new thread with its own store
+///
+/// `cpu` is set for threads that run a cpu rather than a kernel worker.
+pub(crate) fn spawn<F>(
+    engine: &Engine,
+    data: State,
+    name: String,
+    cpu: Option<u32>,
+    entry: F,
+) -> Result<()>
+where
+    F: FnOnce(&mut Store<State>) -> Result<()> + Send + 'static,
+{
+    let engine = engine.clone();
+    let data = State { cpu, ..data };
+    let lifecycle = data.lifecycle.clone();
+    let handle = std::thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || {
+            if data.lifecycle.is_stopped() {
+                return;
+            }
+            let lifecycle = data.lifecycle.clone();
+            let id = lifecycle.register(name.clone(), cpu);
+            let mut store = Store::new(&engine, data);
+            if matches!(store.data().clock.mode(), ClockMode::Virtual) {
+                store.set_fuel(u64::MAX).unwrap();
+                store.data_mut().fuel = u64::MAX;
+            }
+            store.epoch_deadline_trap();
+            store.set_epoch_deadline(1);
+
+            // a thread that unwinds would otherwise never stop the vm
+            match panic::catch_unwind(AssertUnwindSafe(|| entry(&mut store))) {
+                Ok(result) => {
+                    let status = match &result {
+                        Ok(()) => ThreadStatus::Exited,
+                        Err(_) if lifecycle.is_stopped() => ThreadStatus::Stopped,
+                        Err(_) => ThreadStatus::Trapped,
+                    };
+                    handle_result(result, &name, &mut store);
+                    lifecycle.set_status(id, status);
+                }
+                Err(payload) => {
+                    lifecycle.set_status(id, ThreadStatus::Trapped);
+                    console::restore_terminal();
+                    lifecycle.stop(ExitReason::Panic {
+                        thread: name,
+                        message: panic_message(payload),
+                    });
+                }
+            }
+        })?;
+    lifecycle.track(handle);
+    Ok(())
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Ok(message) = payload.downcast::<String>() {
+        *message
+    } else {
+        String::from("unknown panic payload")
+    }
+}
+
+/// stop the vm if a thread trapped
+pub fn handle_result(result: Result<()>, name: &str, store: &mut Store<State>) {
+    let Err(err) = result else {
+        return;
+    };
+
+    let lifecycle = store.data().lifecycle.clone();
+    // traps caused by stopping the vm are expected
+    if err.is::<Stopped>()
+        || (lifecycle.is_stopped() && err.downcast_ref::<Trap>() == Some(&Trap::Interrupt))
+    {
+        return;
+    }
+
+    if let Some(dump) = err.downcast_ref::<WasmCoreDump>() {
+        let core = dump.serialize(store, name);
+        if let Err(err) = std::fs::write("kernel.coredump", core) {
+            eprintln!("while writing coredump: {err}");
+        }
+    }
+
+    // the trap is reported once the terminal is usable again
+    console::restore_terminal();
+    lifecycle.stop(ExitReason::Trap {
+        thread: String::from(name),
+        error: err,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+
+    /// boots until the console has input, then powers off on `h` or, on
+    /// `r`, parks a worker in an untimed wait and reboots
+    const REBOOT_KERNEL: &str = r#"
+    (module
+      (import "env" "memory" (memory 16 16 shared))
+      (import "kernel" "boot_console_read" (func $read (param i32 i32) (result i32)))
+      (import "kernel" "wait_for_interrupt" (func $wait (param i64) (result i32)))
+      (import "kernel" "new_worker" (func $new_worker (param i32 i32 i32)))
+      (import "kernel" "restart" (func $restart))
+      (import "kernel" "halt" (func $halt))
+
+      (func (export "boot")
+        (loop $input
+          (if (i32.eqz (call $read (i32.const 0) (i32.const 1)))
+            (then
+              (drop (call $wait (i64.const 1000000)))
+              (br $input))))
+        (if (i32.ne (i32.load8_u (i32.const 0)) (i32.const 0x72))
+          (then (call $halt)))
+        (call $new_worker (i32.const 0) (i32.const 0) (i32.const 0))
+        (loop $parked
+          (br_if $parked (i32.eqz (i32.atomic.load (i32.const 8)))))
+        (call $restart))
+
+      (func (export "task") (param i32)
+        (i32.atomic.store (i32.const 8) (i32.const 1))
+        (drop (memory.atomic.wait32 (i32.const 16) (i32.const 0) (i64.const -1)))))
+    "#;
+
+    /// wait for the vm on another thread, so a hang fails the test
+    fn wait(vm: Vm) -> ExitReason {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || sender.send(vm.wait().unwrap()));
+        receiver
+            .recv_timeout(Duration::from_secs(30))
+            .expect("the vm did not stop")
+    }
+
+    #[test]
+    fn panics_stop_the_vm() {
+        // the stack trace does not fit past the end of memory
+        let kernel = r#"
+        (module
+          (import "env" "memory" (memory 16 16 shared))
+          (import "kernel" "get_stacktrace" (func $stacktrace (param i32 i32)))
+          (func (export "boot")
+            (call $stacktrace (i32.const -16) (i32.const 16))))
+        "#;
+        let vm = VmBuilder::from_bytes(kernel)
+            .memory(1)
+            .cpus(1)
+            .build()
+            .unwrap();
+        let reason = wait(vm);
+        let ExitReason::Panic { thread, .. } = reason else {
+            panic!("the vm stopped with {reason}");
+        };
+        assert_eq!(thread, "boot");
+    }
+
+    #[test]
+    fn reboots_past_threads_in_untimed_waits() {
+        let vm = VmBuilder::from_bytes(REBOOT_KERNEL)
+            .memory(1)
+            .cpus(1)
+            .build()
+            .unwrap();
+        // the first boot takes the r, the second the h
+        vm.machine.console.push_input(b"rh");
+        assert!(matches!(wait(vm), ExitReason::PowerOff));
+    }
+}